@@ -28,6 +28,7 @@ server-launcher-port-label = Launcher Port
 server-game-port-label = Game Port
 server-game-folder-label = Server Game Path
 server-game-version-label = Version
+server-patch-key-label = Patch Signing Key
 
 # Messages
 announcements-label = Announcements
@@ -51,6 +52,8 @@ fullscreen-resolution-label = Fullscreen Resolution
 list-remote-servers-label = List Remote Servers
 list-remote-messages-label = List Global Messages
 serverlist-url-label = Serverlist URL
+patcher-concurrency-label = Parallel Patch Downloads
+patcher-rate-limit-label = Patch Download Limit (KB/s, 0 = unlimited)
 settings-error = Failed to write settings to 'mhf.ini'
 
 # Characters page
@@ -80,7 +83,31 @@ patcher-updates-confirmation =
 patcher-checking = Checking updates...
 patcher-percentage = [{ $percentage }%]Downloading files...
 patcher-progress = { $current } out of { $total } files downloaded
+patcher-speed = { $speed }/s, { $eta } remaining
 patcher-patching = Finishing...
+patcher-repair-label = Game Files
+patcher-repair-button = Verify and Repair
+patcher-repair-done = Verified game files, { $count } files repaired
+patcher-exclusions-label = Files Never Patched
+patcher-exclusions-placeholder = One pattern per line, e.g. mhf.ini
+patcher-excluded-outdated = { $count } excluded files are out of date: { $files }
+patcher-changes-button = Show Changes
+patcher-changes-none = No files need to be changed
+patcher-changes-added = Added ({ $count })
+patcher-changes-replaced = Replaced ({ $count })
+patcher-changes-deleted = Deleted ({ $count })
+patcher-changes-excluded = Excluded, not patched ({ $count })
+patcher-history-label = Roll Back Patch
+patcher-history-entry = { $date } { $etag } ({ $count } files)
+patcher-rollback-button = Roll Back
+patcher-rollback-done = Rolled back game files to the version before { $etag }
+patcher-shared-store-label = Share Patch Files Between Game Folders
+patcher-shared-store-hardlinks-label = Link Shared Files Instead of Copying (editing a linked file changes it in every game folder)
+patcher-shared-store-clean-button = Clean Up
+patcher-shared-store-cleaned = Removed unused shared patch files, { $size } freed
+patcher-bundle-label = Offline Patch Bundle
+patcher-bundle-import-button = Import
+patcher-bundle-export-button = Export Pending Patch
 
 # Weapons
 greatsword-label = Greatsword
@@ -102,6 +129,7 @@ magnet-spike-label = Magnet Spike
 endpoint-name-empty = Server name must not be empty
 endpoint-host-empty = Server host must not be empty
 endpoint-unique = Server names must be unique
+endpoint-patch-key-invalid = Patch signing key must be a base64 Ed25519 public key
 file-error = Failed to manage files
 path-folder-error = Path must be a directory
 path-exists-error = The specified game folder does not exist
@@ -111,6 +139,27 @@ remote-messages-error = Unable to fetch global messages
 launcher-network-error = Launcher failed to connect to launcher server
 patcher-network-error = Patcher failed to connect to patcher server
 patcher-file-error = Patcher failed to manage files in game folder
+patcher-file-path-error = Patcher failed to access "{ $path }": { $error }
+patcher-hash-error = Downloaded file { $path } did not match the expected checksum
+patcher-manifest-error = Patch manifest is invalid or uses an unsupported format
+patcher-manifest-line-error = Patch manifest is invalid at line { $line }
+patcher-duplicate-path-error = Patch manifest lists the file "{ $path }" more than once
+patcher-signature-error = Patch manifest signature is missing or invalid
+patcher-bundle-error = Patch bundle is invalid or does not match this game folder
+patcher-export-empty = There is no pending patch to export
+patcher-changes-empty = There is no pending patch to check
+patcher-rollback-error = The backup needed to roll back this patch is no longer available
+patcher-unsafe-path-error = Patch rejected, the file path "{ $path }" points outside of the game folder or at a reserved name
+patcher-exclude-error = Invalid file pattern in patch exclusions
+patcher-disk-space-error = Not enough disk space to patch, { $required } MB required but only { $available } MB available
+patcher-download-error =
+    { $retries ->
+        [0] Patcher failed to download { $path }
+       *[other] Patcher failed to download { $path } after { $retries } retries
+    }{ $status ->
+        [none] {""}
+       *[other] {" "}(HTTP { $status })
+    }
 internal-error = Launcher error, check logs
 
 # Remote
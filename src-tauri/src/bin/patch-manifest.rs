@@ -0,0 +1,297 @@
+//! Generates the patch manifest of a client folder, for server operators.
+//!
+//! ```text
+//! patch-manifest <folder> [--output <file>] [--json] [--serve <addr>]
+//! ```
+//!
+//! The manifest lists every file as a `hash\tpath` line, sorted by path so that
+//! the same folder always produces the same manifest and ETag. With `--json`,
+//! it is written in format 2 instead, which also lists file sizes so that the
+//! launcher can check for free disk space before downloading. With `--serve`,
+//! the folder is also served the way a patch server would, which is enough to
+//! point a local launcher at it for testing.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    thread,
+};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+const USAGE: &str = "usage: patch-manifest <folder> [--output <file>] [--json] [--serve <addr>]";
+/// Prefix of the files the launcher keeps in the game folder, which it never
+/// accepts from a manifest.
+const LAUNCHER_PREFIX: &str = "patcher.";
+/// Hex digits of the manifest hash used as its ETag.
+const ETAG_LEN: usize = 16;
+
+struct Args {
+    folder: PathBuf,
+    output: Option<PathBuf>,
+    /// Writes a format 2 JSON manifest with file sizes.
+    json: bool,
+    serve: Option<String>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut folder = None;
+        let mut output = None;
+        let mut json = false;
+        let mut serve = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--output" => output = Some(args.next().ok_or("--output requires a path")?.into()),
+                "--json" => json = true,
+                "--serve" => serve = Some(args.next().ok_or("--serve requires an address")?),
+                _ if arg.starts_with("--") || folder.is_some() => {
+                    return Err(format!("unknown argument '{}'", arg))
+                }
+                _ => folder = Some(PathBuf::from(arg)),
+            }
+        }
+        let folder = folder.ok_or("missing folder")?;
+        if !folder.is_dir() {
+            return Err(format!("{:?} is not a directory", folder));
+        }
+        Ok(Self {
+            folder,
+            output,
+            json,
+            serve,
+        })
+    }
+}
+
+/// Manifest of a folder, along with the ETag the launcher stores once it is
+/// applied.
+struct Manifest {
+    content: String,
+    etag: String,
+    /// Manifest paths, which are the only files served.
+    paths: Vec<String>,
+}
+
+/// Entry of a format 2 JSON manifest.
+#[derive(Serialize)]
+struct JsonFile<'a> {
+    path: &'a str,
+    hash: String,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct JsonManifest<'a> {
+    format: u32,
+    files: Vec<JsonFile<'a>>,
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Collects the `/`-separated paths of every file under `folder`.
+fn walk(folder: &Path, prefix: &str, paths: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(folder)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if prefix.is_empty() && name.to_ascii_lowercase().starts_with(LAUNCHER_PREFIX) {
+            continue;
+        }
+        let path = format!("{}{}", prefix, name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&entry.path(), &format!("{}/", path), paths)?;
+        } else if file_type.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn generate(folder: &Path, skip: Option<&Path>, json: bool) -> io::Result<Manifest> {
+    let mut paths = Vec::new();
+    walk(folder, "", &mut paths)?;
+    if let Some(skip) = skip.and_then(|skip| skip.canonicalize().ok()) {
+        paths.retain(|path| folder.join(path).canonicalize().ok().as_ref() != Some(&skip));
+    }
+    paths.sort();
+    let mut content = String::new();
+    let mut files = Vec::new();
+    for path in &paths {
+        let hash = hash_file(&folder.join(path))?;
+        if json {
+            let size = fs::metadata(folder.join(path))?.len();
+            files.push(JsonFile { path, hash, size });
+        } else {
+            content += &format!("{}\t{}\n", hash, path);
+        }
+    }
+    if json {
+        content = serde_json::to_string_pretty(&JsonManifest { format: 2, files })?;
+        content.push('\n');
+    }
+    let etag = format!("{:x}", Sha256::digest(content.as_bytes()))[..ETAG_LEN].to_owned();
+    Ok(Manifest {
+        content,
+        etag,
+        paths,
+    })
+}
+
+/// Decodes the `%XX` escapes of a request path, such as `a%20b.pac`.
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, String)],
+    mut body: impl Read,
+) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    for (name, value) in headers {
+        head += &format!("{}: {}\r\n", name, value);
+    }
+    head += "\r\n";
+    stream.write_all(head.as_bytes())?;
+    io::copy(&mut body, stream)?;
+    stream.flush()
+}
+
+/// Answers a single request the way a patch server does: the manifest from
+/// `/check` and files by their manifest path, continuing from the offset of a
+/// `Range: bytes=<offset>-` header.
+fn handle(mut stream: TcpStream, folder: &Path, manifest: &Manifest) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut if_none_match = None;
+    let mut range_start = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("if-none-match") {
+            if_none_match = Some(value.to_owned());
+        } else if name.eq_ignore_ascii_case("range") {
+            range_start = value
+                .strip_prefix("bytes=")
+                .and_then(|range| range.strip_suffix('-'))
+                .and_then(|start| start.parse::<u64>().ok());
+        }
+    }
+    let target = request_line.split_whitespace().nth(1).unwrap_or_default();
+    eprintln!("{}", request_line.trim());
+
+    if target == "/check" {
+        if if_none_match.as_deref() == Some(manifest.etag.as_str()) {
+            return write_response(&mut stream, "304 Not Modified", &[], io::empty());
+        }
+        let headers = [
+            ("ETag", manifest.etag.clone()),
+            ("Content-Length", manifest.content.len().to_string()),
+        ];
+        return write_response(&mut stream, "200 OK", &headers, manifest.content.as_bytes());
+    }
+    let path = percent_decode(target.trim_start_matches('/'));
+    // Files deleted since the manifest was generated are missing too.
+    let file = path
+        .filter(|path| manifest.paths.iter().any(|p| p == path))
+        .and_then(|path| fs::File::open(folder.join(path)).ok());
+    let Some(mut file) = file else {
+        return write_response(&mut stream, "404 Not Found", &[], io::empty());
+    };
+    let len = file.metadata()?.len();
+    match range_start {
+        Some(start) if start >= len => {
+            write_response(&mut stream, "416 Range Not Satisfiable", &[], io::empty())
+        }
+        Some(start) => {
+            file.seek(SeekFrom::Start(start))?;
+            let headers = [
+                ("Content-Length", (len - start).to_string()),
+                (
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", start, len - 1, len),
+                ),
+            ];
+            write_response(&mut stream, "206 Partial Content", &headers, file)
+        }
+        None => {
+            let headers = [("Content-Length", len.to_string())];
+            write_response(&mut stream, "200 OK", &headers, file)
+        }
+    }
+}
+
+fn serve(addr: &str, folder: PathBuf, manifest: Manifest) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    eprintln!("serving {:?} on http://{}", folder, listener.local_addr()?);
+    let shared = Arc::new((folder, manifest));
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let (folder, manifest) = &*shared;
+            if let Err(e) = handle(stream, folder, manifest) {
+                eprintln!("request failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn run(args: Args) -> io::Result<()> {
+    let manifest = generate(&args.folder, args.output.as_deref(), args.json)?;
+    match &args.output {
+        Some(output) => fs::write(output, &manifest.content)?,
+        None => io::stdout().write_all(manifest.content.as_bytes())?,
+    }
+    eprintln!("{} files, ETag {}", manifest.paths.len(), manifest.etag);
+    if let Some(addr) = &args.serve {
+        serve(addr, args.folder, manifest)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(args) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
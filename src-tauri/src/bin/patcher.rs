@@ -0,0 +1,231 @@
+//! Patches a game folder without opening the launcher, so that server admins
+//! can prepare clients from scripts.
+//!
+//! ```text
+//! patcher --server <url>... --folder <dir> [--key <base64>] [--verify]
+//! ```
+//!
+//! This is a console program rather than a mode of the launcher, so that
+//! shells wait for it and see its exit code.
+
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    process,
+    sync::Mutex,
+};
+
+use app_lib::{
+    limiter::RateLimiter,
+    manifest,
+    mirror::{MirrorHealth, Mirrors},
+    patcher::{self, PatchJob, PatchSource, PatcherOptions},
+    progress::{PatcherEvent, ProgressSink, State},
+    server, LogPayload,
+};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use tokio_util::sync::CancellationToken;
+
+const USAGE: &str = "usage: patcher --server <url>... --folder <dir> [--key <base64>] [--verify]";
+
+/// Exit codes of the patcher.
+const EXIT_SUCCESS: i32 = 0;
+const EXIT_PATCH_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+/// No patch server sent back a manifest.
+const EXIT_NETWORK: i32 = 3;
+
+#[derive(Debug)]
+struct PatchArgs {
+    /// Patch servers in the order they are tried, the primary one first.
+    servers: Vec<String>,
+    folder: PathBuf,
+    /// Ed25519 public key the manifest signature is checked against, like the
+    /// patch key of an endpoint.
+    key: Option<String>,
+    /// Rehashes every file, like "Verify and Repair" in the launcher.
+    verify: bool,
+}
+
+impl PatchArgs {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut servers = Vec::new();
+        let mut folder = None;
+        let mut key = None;
+        let mut verify = false;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--server" => {
+                    let server = args.next().ok_or("--server requires a URL")?;
+                    servers.push(server.trim_end_matches('/').to_owned());
+                }
+                "--folder" => folder = Some(args.next().ok_or("--folder requires a path")?),
+                "--key" => {
+                    let value = args.next().ok_or("--key requires a base64 public key")?;
+                    if manifest::parse_key(&value).is_none() {
+                        return Err(format!("invalid public key '{}'", value));
+                    }
+                    key = Some(value);
+                }
+                "--verify" => verify = true,
+                _ => return Err(format!("unknown argument '{}'", arg)),
+            }
+        }
+        if servers.is_empty() {
+            return Err("missing --server".into());
+        }
+        let folder = PathBuf::from(folder.ok_or("missing --folder")?);
+        if !folder.is_dir() {
+            return Err(format!("{:?} is not a directory", folder));
+        }
+        Ok(Self {
+            servers,
+            folder,
+            key,
+            verify,
+        })
+    }
+}
+
+/// Prints patcher progress to the terminal, one line per state and for every
+/// tenth of the files or bytes processed.
+#[derive(Default)]
+struct TerminalSink {
+    last: Mutex<Option<(State, u64)>>,
+}
+
+impl ProgressSink for TerminalSink {
+    fn send_event(&self, event: &PatcherEvent) {
+        let (current, total) = if event.total_bytes > 0 {
+            (event.current_bytes, event.total_bytes)
+        } else {
+            (event.current as u64, event.total as u64)
+        };
+        let step = current
+            .saturating_mul(10)
+            .checked_div(total)
+            .unwrap_or(0)
+            .min(10);
+        let mut last = self.last.lock().unwrap();
+        if last.is_some_and(|(state, last_step)| state == event.state && last_step == step) {
+            return;
+        }
+        *last = Some((event.state, step));
+        let label = match event.state {
+            State::Checking => "checking",
+            State::Downloading => "downloading",
+            State::Patching => "patching",
+            State::Done => "done",
+            State::Error => return,
+        };
+        let mut line = label.to_owned();
+        if event.total > 0 {
+            line += &format!(" {}/{} files", event.current, event.total);
+        }
+        if event.total_bytes > 0 {
+            line += &format!(
+                ", {:.1}/{:.1} MB",
+                event.current_bytes as f64 / 1_000_000.0,
+                event.total_bytes as f64 / 1_000_000.0,
+            );
+        }
+        if event.state == State::Downloading && event.speed > 0 {
+            line += &format!(", {:.1} MB/s", event.speed as f64 / 1_000_000.0);
+            if let Some(eta) = event.eta {
+                line += &format!(", {}s left", eta);
+            }
+        }
+        println!("{}", line);
+        io::stdout().flush().ok();
+    }
+
+    fn send_log(&self, payload: LogPayload) {
+        match payload.args {
+            Some(args) => eprintln!("{}: {} {}", payload.level, payload.message, args),
+            None => eprintln!("{}: {}", payload.level, payload.message),
+        }
+    }
+
+    fn send_excluded(&self, excluded: &[String]) {
+        eprintln!("warning: excluded files are out of date: {:?}", excluded);
+    }
+
+    fn send_repaired(&self, repaired: &[String]) {
+        println!("repaired {} files: {:?}", repaired.len(), repaired);
+    }
+}
+
+/// Prints warnings and errors to stderr, which is where the full detail of a
+/// failed patch ends up.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+async fn patch(args: PatchArgs) -> i32 {
+    let client = reqwest::ClientBuilder::new().gzip(true).build().unwrap();
+    let cancel = CancellationToken::new();
+    // An empty ETag never matches, so verifying always gets the manifest.
+    let etag = if args.verify {
+        String::new()
+    } else {
+        patcher::get_etag(&args.folder)
+    };
+    let patcher_req = server::patcher_request(
+        &client,
+        cancel.clone(),
+        &args.servers,
+        &etag,
+        args.key.as_deref(),
+    );
+    let patcher_resp = match patcher_req.send().await {
+        Ok(Some(patcher_resp)) => patcher_resp,
+        Ok(None) => {
+            println!("game folder is up to date");
+            return EXIT_SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: failed to fetch patch manifest: {}", e);
+            return EXIT_NETWORK;
+        }
+    };
+    let job = PatchJob {
+        source: PatchSource::Mirrors(Mirrors::new(args.servers, MirrorHealth::default())),
+        limiter: RateLimiter::default(),
+        patcher_resp,
+        game_folder: args.folder,
+        options: PatcherOptions::default(),
+        repair: args.verify,
+        export: None,
+    };
+    if patcher::patch(TerminalSink::default(), client, job, cancel).await {
+        EXIT_SUCCESS
+    } else {
+        EXIT_PATCH_FAILED
+    }
+}
+
+fn main() {
+    if log::set_logger(&StderrLogger).is_ok() {
+        log::set_max_level(LevelFilter::Warn);
+    }
+    let args = match PatchArgs::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n{}", e, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    process::exit(tauri::async_runtime::block_on(patch(args)));
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use log::warn;
+use tokio_util::sync::CancellationToken;
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{index, patcher::PatchError, server::PatcherResponse};
+
+pub const BUNDLE_ERROR: &str = "patcher-bundle-error";
+
+const MANIFEST_ENTRY: &str = "manifest";
+const ETAG_ENTRY: &str = "etag";
+const SIGNATURE_ENTRY: &str = "signature";
+const FILES_PREFIX: &str = "files/";
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Offline patch bundle, a zip or tar archive holding the manifest exactly as
+/// served by the patch server along with the files it lists:
+///
+/// ```text
+/// manifest         manifest body
+/// etag             ETag of the manifest
+/// signature        optional base64 signature of the manifest
+/// files/<path>     patch files, by manifest path
+/// ```
+enum Bundle {
+    Zip(ZipArchive<fs::File>),
+    Tar(fs::File),
+}
+
+impl Bundle {
+    fn open(path: &Path) -> Result<Self, PatchError> {
+        let mut file = fs::File::open(path).map_err(PatchError::io(path))?;
+        let mut magic = [0; 4];
+        let is_zip = file.read_exact(&mut magic).is_ok() && magic == ZIP_MAGIC;
+        let file = fs::File::open(path).map_err(PatchError::io(path))?;
+        if is_zip {
+            let archive = ZipArchive::new(file).map_err(|e| {
+                warn!("failed to read patch bundle: {}", e);
+                BUNDLE_ERROR
+            })?;
+            Ok(Self::Zip(archive))
+        } else {
+            Ok(Self::Tar(file))
+        }
+    }
+
+    /// Calls `f` with the name and contents of every entry until it returns
+    /// `false`.
+    fn for_each<F>(self, mut f: F) -> Result<(), PatchError>
+    where
+        F: FnMut(&str, &mut dyn Read) -> Result<bool, PatchError>,
+    {
+        match self {
+            Self::Zip(mut archive) => {
+                for i in 0..archive.len() {
+                    let mut entry = archive.by_index(i).or(Err(BUNDLE_ERROR))?;
+                    let name = entry.name().to_owned();
+                    if !f(&name, &mut entry)? {
+                        break;
+                    }
+                }
+            }
+            Self::Tar(file) => {
+                let mut archive = tar::Archive::new(file);
+                for entry in archive.entries().or(Err(BUNDLE_ERROR))? {
+                    let mut entry = entry.or(Err(BUNDLE_ERROR))?;
+                    let name = entry
+                        .path()
+                        .or(Err(BUNDLE_ERROR))?
+                        .to_string_lossy()
+                        .replace('\\', "/");
+                    let name = name.trim_start_matches("./");
+                    if !f(name, &mut entry)? {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_string(reader: &mut dyn Read) -> Result<String, PatchError> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content).or(Err(BUNDLE_ERROR))?;
+    Ok(content)
+}
+
+/// Reads the manifest of the bundle at `path`.
+pub fn read_manifest(path: &Path) -> Result<PatcherResponse, PatchError> {
+    let mut etag = None;
+    let mut content = None;
+    let mut signature = None;
+    Bundle::open(path)?.for_each(|name, reader| {
+        match name {
+            MANIFEST_ENTRY => content = Some(read_string(reader)?),
+            ETAG_ENTRY => etag = Some(read_string(reader)?.trim().to_owned()),
+            SIGNATURE_ENTRY => signature = Some(read_string(reader)?.trim().to_owned()),
+            _ => {}
+        }
+        Ok(etag.is_none() || content.is_none() || signature.is_none())
+    })?;
+    let (Some(etag), Some(content)) = (etag, content) else {
+        warn!("patch bundle has no manifest");
+        return Err(BUNDLE_ERROR.into());
+    };
+    Ok(PatcherResponse {
+        etag,
+        content,
+        signature,
+    })
+}
+
+/// Extracts the `(path, hash)` files from the bundle at `path` into
+/// `patcher_folder`, checking each one against its hash and calling `on_file`
+/// with its size. Returns `false` if cancelled.
+pub fn extract(
+    path: &Path,
+    files: &[(String, String)],
+    patcher_folder: &Path,
+    cancel: &CancellationToken,
+    mut on_file: impl FnMut(u64),
+) -> Result<bool, PatchError> {
+    let mut pending: HashMap<&str, &str> = files
+        .iter()
+        .map(|(path, hash)| (path.as_str(), hash.as_str()))
+        .collect();
+    Bundle::open(path)?.for_each(|name, reader| {
+        if cancel.is_cancelled() {
+            return Ok(false);
+        }
+        let Some((path, hash)) = name
+            .strip_prefix(FILES_PREFIX)
+            .and_then(|path| pending.remove_entry(path))
+        else {
+            return Ok(true);
+        };
+        let staged_path = patcher_folder.join(path);
+        let parent = staged_path.parent().ok_or(BUNDLE_ERROR)?;
+        fs::create_dir_all(parent).map_err(PatchError::io(parent))?;
+        let mut file = fs::File::create(&staged_path).map_err(PatchError::io(&staged_path))?;
+        let size = io::copy(reader, &mut file).map_err(|e| {
+            warn!("failed to extract {} from patch bundle: {}", path, e);
+            BUNDLE_ERROR
+        })?;
+        drop(file);
+        let staged_hash = index::hash_file(&staged_path).map_err(PatchError::io(&staged_path))?;
+        if staged_hash != hash {
+            warn!(
+                "hash mismatch for {} in patch bundle: expected {}, got {}",
+                path, hash, staged_hash
+            );
+            return Err(BUNDLE_ERROR.into());
+        }
+        on_file(size);
+        Ok(!pending.is_empty())
+    })?;
+    if cancel.is_cancelled() {
+        return Ok(false);
+    }
+    if !pending.is_empty() {
+        warn!("patch bundle is missing files: {:?}", pending.keys());
+        return Err(BUNDLE_ERROR.into());
+    }
+    Ok(true)
+}
+
+/// Writes a zip bundle to `path` with the manifest in `patcher_resp` and the
+/// staged `files` from `patcher_folder`.
+pub fn write(
+    path: &Path,
+    patcher_resp: &PatcherResponse,
+    files: &[String],
+    patcher_folder: &Path,
+) -> Result<(), PatchError> {
+    let file = fs::File::create(path).map_err(PatchError::io(path))?;
+    let mut writer = ZipWriter::new(file);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut entries = vec![
+        (MANIFEST_ENTRY, &patcher_resp.content),
+        (ETAG_ENTRY, &patcher_resp.etag),
+    ];
+    if let Some(signature) = &patcher_resp.signature {
+        entries.push((SIGNATURE_ENTRY, signature));
+    }
+    // Write failures are reported against the bundle, read failures against
+    // the staged file.
+    let write_error = |e: zip::result::ZipError| PatchError::Io {
+        path: path.to_owned(),
+        source: e.into(),
+    };
+    for (name, content) in entries {
+        writer.start_file(name, options).map_err(write_error)?;
+        io::Write::write_all(&mut writer, content.as_bytes()).map_err(PatchError::io(path))?;
+    }
+    for file_path in files {
+        let staged_path = patcher_folder.join(file_path);
+        let mut staged = fs::File::open(&staged_path).map_err(PatchError::io(&staged_path))?;
+        let len = staged
+            .metadata()
+            .map_err(PatchError::io(&staged_path))?
+            .len();
+        writer
+            .start_file(
+                format!("{}{}", FILES_PREFIX, file_path),
+                options.large_file(len >= u32::MAX as u64),
+            )
+            .map_err(write_error)?;
+        io::copy(&mut staged, &mut writer).map_err(PatchError::io(path))?;
+    }
+    writer.finish().map_err(write_error)?;
+    Ok(())
+}
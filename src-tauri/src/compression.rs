@@ -0,0 +1,81 @@
+use std::{
+    fs,
+    io::{self, BufReader, Write},
+    mem,
+    path::Path,
+};
+
+use serde::Deserialize;
+
+/// Compression of a file stored on the patch server, which serves it under its
+/// manifest path followed by the compression's extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Zstd => ".zst",
+            Self::Gzip => ".gz",
+        }
+    }
+}
+
+/// Largest window a delta may use, which covers files up to 2 GB.
+const DELTA_WINDOW_LOG_MAX: u32 = 31;
+
+/// Rebuilds `target` from the `reference` file it was diffed against and a
+/// zstd delta created with `zstd --patch-from=<reference> <target>`.
+pub fn apply_delta(reference: &Path, delta: &Path, target: &Path) -> io::Result<()> {
+    let reference = fs::read(reference)?;
+    let delta = BufReader::new(fs::File::open(delta)?);
+    let mut decoder = zstd::stream::read::Decoder::with_ref_prefix(delta, &reference)?;
+    decoder.window_log_max(DELTA_WINDOW_LOG_MAX)?;
+    let mut file = fs::File::create(target)?;
+    io::copy(&mut decoder, &mut file)?;
+    file.sync_all()
+}
+
+/// Decompresses a download on the fly, one chunk at a time as it arrives.
+pub enum Decoder {
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+}
+
+impl Decoder {
+    pub fn new(compression: Compression) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::Zstd => Self::Zstd(zstd::stream::write::Decoder::new(Vec::new())?),
+            Compression::Gzip => Self::Gzip(flate2::write::GzDecoder::new(Vec::new())),
+        })
+    }
+
+    /// Decompresses `chunk`, returning the output that is ready so far.
+    pub fn decode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd(decoder) => {
+                decoder.write_all(chunk)?;
+                Ok(mem::take(decoder.get_mut()))
+            }
+            Self::Gzip(decoder) => {
+                decoder.write_all(chunk)?;
+                Ok(mem::take(decoder.get_mut()))
+            }
+        }
+    }
+
+    /// Returns the remaining output once the whole file was decoded.
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+            Self::Gzip(decoder) => decoder.finish(),
+        }
+    }
+}
@@ -2,6 +2,8 @@ use std::{collections::HashMap, path::PathBuf, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
+use crate::manifest;
+
 #[derive(Default, Clone, Serialize, Deserialize)]
 pub struct EndpointConfig {
     pub game_folder: Option<PathBuf>,
@@ -17,6 +19,10 @@ pub struct Endpoint {
     pub game_folder: Option<PathBuf>,
     pub version: mhf_iel::MhfVersion,
     #[serde(default)]
+    pub patch_key: Option<String>,
+    #[serde(default)]
+    pub patch_mirrors: Vec<String>,
+    #[serde(default)]
     pub is_remote: bool,
 }
 
@@ -60,6 +66,12 @@ impl EndpointVecExt for Vec<Endpoint> {
                 return Err("endpoint-host-empty");
             } else if self.iter().filter(|e| e.name == endpoint.name).count() > 1 {
                 return Err("endpoint-unique");
+            } else if endpoint
+                .patch_key
+                .as_deref()
+                .is_some_and(|k| manifest::parse_key(k).is_none())
+            {
+                return Err("endpoint-patch-key-invalid");
             }
             if let Some(game_folder) = endpoint.game_folder.as_ref() {
                 if !game_folder.exists() {
@@ -0,0 +1,71 @@
+use std::{fs, path::Path};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use log::warn;
+
+pub const EXCLUDE_ERROR: &str = "patcher-exclude-error";
+
+const EXCLUDE_FILE: &str = "patcher.exclude";
+
+/// Glob patterns of game files the patcher must leave alone, such as
+/// customised configs or fan translations, stored one per line in the game
+/// folder. Lines starting with `#` are comments.
+#[derive(Debug, Default)]
+pub struct Exclusions {
+    set: GlobSet,
+}
+
+fn build(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        // Game folders live on case-insensitive file systems.
+        builder.add(GlobBuilder::new(pattern).case_insensitive(true).build()?);
+    }
+    builder.build()
+}
+
+impl Exclusions {
+    pub fn patterns(game_folder: &Path) -> Vec<String> {
+        fs::read_to_string(game_folder.join(EXCLUDE_FILE))
+            .unwrap_or_default()
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Fails on an invalid pattern rather than ignoring it, since patching
+    /// without exclusions would overwrite the files they protect.
+    pub fn load(game_folder: &Path) -> Result<Self, &'static str> {
+        let set = build(&Self::patterns(game_folder)).map_err(|e| {
+            warn!("invalid patcher exclusions: {}", e);
+            EXCLUDE_ERROR
+        })?;
+        Ok(Self { set })
+    }
+
+    pub fn save(game_folder: &Path, patterns: &[String]) -> Result<(), &'static str> {
+        build(patterns).map_err(|e| {
+            warn!("invalid patcher exclusion: {}", e);
+            EXCLUDE_ERROR
+        })?;
+        let path = game_folder.join(EXCLUDE_FILE);
+        let result = if patterns.is_empty() {
+            fs::remove_file(path).or_else(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            })
+        } else {
+            fs::write(path, patterns.join("\n") + "\n")
+        };
+        result.map_err(|e| {
+            warn!("failed to save patcher exclusions: {}", e);
+            "file-error"
+        })
+    }
+
+    pub fn is_excluded(&self, path: &str) -> bool {
+        self.set.is_match(path)
+    }
+}
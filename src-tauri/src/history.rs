@@ -0,0 +1,146 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::patcher::PatchError;
+
+pub const ROLLBACK_ERROR: &str = "patcher-rollback-error";
+
+const HISTORY_FILE: &str = "patcher.history";
+const VERSIONS_FOLDER: &str = "patcher.versions";
+/// Number of most recent patches whose replaced files are kept for rollbacks.
+const MAX_BACKUPS: usize = 3;
+const MAX_ENTRIES: usize = 50;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Change {
+    Added,
+    Replaced,
+    Deleted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryFile {
+    pub path: String,
+    pub change: Change,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub id: u64,
+    /// ETag of the game folder before the patch was applied.
+    pub previous_etag: String,
+    pub etag: String,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub files: Vec<HistoryFile>,
+    /// Whether the files replaced by the patch are still kept, so that it can
+    /// be rolled back.
+    pub has_backup: bool,
+}
+
+/// Patches applied to a game folder, oldest first, along with a backup of the
+/// files replaced by the most recent ones.
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<HistoryEntry>,
+}
+
+impl History {
+    pub fn load(game_folder: &Path) -> Self {
+        let entries = fs::read(game_folder.join(HISTORY_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self { entries }
+    }
+
+    fn save(&self, game_folder: &Path) -> Result<(), PatchError> {
+        let path = game_folder.join(HISTORY_FILE);
+        let data = serde_json::to_vec(&self.entries).map_err(|e| PatchError::Io {
+            path: path.clone(),
+            source: e.into(),
+        })?;
+        fs::write(&path, data).map_err(PatchError::io(&path))
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    /// Folder holding the files replaced by the patch `id`, by manifest path.
+    pub fn backup_folder(game_folder: &Path, id: u64) -> PathBuf {
+        game_folder.join(VERSIONS_FOLDER).join(id.to_string())
+    }
+
+    /// Records an applied patch, moving `backup_folder` with the files it
+    /// replaced into the history.
+    pub fn record(
+        &mut self,
+        game_folder: &Path,
+        previous_etag: String,
+        etag: String,
+        files: Vec<HistoryFile>,
+        backup_folder: &Path,
+    ) -> Result<(), PatchError> {
+        let id = self.entries.last().map_or(1, |entry| entry.id + 1);
+        let target = Self::backup_folder(game_folder, id);
+        let moved = if backup_folder.exists() {
+            fs::create_dir_all(game_folder.join(VERSIONS_FOLDER))
+                .and_then(|_| fs::rename(backup_folder, &target))
+        } else {
+            fs::create_dir_all(&target)
+        };
+        if let Err(e) = &moved {
+            warn!("failed to keep backup of patch {}: {}", etag, e);
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.entries.push(HistoryEntry {
+            id,
+            previous_etag,
+            etag,
+            timestamp,
+            files,
+            has_backup: moved.is_ok(),
+        });
+        self.prune(game_folder);
+        self.save(game_folder)
+    }
+
+    /// Forgets the latest patch once it was rolled back.
+    pub fn pop(&mut self, game_folder: &Path) -> Result<(), PatchError> {
+        if let Some(entry) = self.entries.pop() {
+            remove_backup(game_folder, entry.id);
+        }
+        self.save(game_folder)
+    }
+
+    fn prune(&mut self, game_folder: &Path) {
+        let backups = self.entries.len().saturating_sub(MAX_BACKUPS);
+        for entry in &mut self.entries[..backups] {
+            if entry.has_backup {
+                remove_backup(game_folder, entry.id);
+                entry.has_backup = false;
+            }
+        }
+        let excess = self.entries.len().saturating_sub(MAX_ENTRIES);
+        self.entries.drain(..excess);
+    }
+}
+
+fn remove_backup(game_folder: &Path, id: u64) {
+    let folder = History::backup_folder(game_folder, id);
+    if folder.exists() {
+        fs::remove_dir_all(&folder)
+            .unwrap_or_else(|e| warn!("failed to remove patch backup {:?}: {}", folder, e));
+    }
+}
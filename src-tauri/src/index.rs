@@ -0,0 +1,109 @@
+use std::{collections::HashMap, fs, io, path::Path, time::SystemTime};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+
+const INDEX_FILE: &str = "patcher.index";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    size: u64,
+    modified: SystemTime,
+    hash: String,
+}
+
+/// Persisted hashes of the files in a game folder, keyed by their manifest path.
+///
+/// An entry is only trusted while the file keeps the size and modification
+/// time it had when it was hashed, so files edited outside of the launcher
+/// are always read again.
+#[derive(Debug, Default)]
+pub struct HashIndex {
+    entries: HashMap<String, IndexEntry>,
+    dirty: bool,
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = sha2::Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+impl HashIndex {
+    pub fn load(game_folder: &Path) -> Self {
+        let entries = fs::read(game_folder.join(INDEX_FILE))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Self {
+            entries,
+            dirty: false,
+        }
+    }
+
+    pub fn save(&mut self, game_folder: &Path) {
+        if !self.dirty {
+            return;
+        }
+        let result = serde_json::to_vec(&self.entries)
+            .map_err(io::Error::from)
+            .and_then(|data| fs::write(game_folder.join(INDEX_FILE), data));
+        match result {
+            Ok(_) => self.dirty = false,
+            Err(e) => warn!("failed to save hash index: {}", e),
+        }
+    }
+
+    /// Returns the hash of `path`, only reading the file if it changed since it
+    /// was last hashed.
+    pub fn hash(&mut self, game_folder: &Path, path: &str) -> io::Result<String> {
+        let client_path = game_folder.join(path);
+        let metadata = fs::metadata(&client_path)?;
+        let modified = metadata.modified()?;
+        if let Some(entry) = self.entries.get(path) {
+            if entry.size == metadata.len() && entry.modified == modified {
+                return Ok(entry.hash.clone());
+            }
+        }
+        let hash = hash_file(&client_path)?;
+        self.entries.insert(
+            path.to_owned(),
+            IndexEntry {
+                size: metadata.len(),
+                modified,
+                hash: hash.clone(),
+            },
+        );
+        self.dirty = true;
+        Ok(hash)
+    }
+
+    pub fn into_hashes(self) -> impl Iterator<Item = String> {
+        self.entries.into_values().map(|entry| entry.hash)
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        self.dirty |= self.entries.remove(path).is_some();
+    }
+
+    /// Records the already known hash of a file the patcher just wrote.
+    pub fn insert(&mut self, game_folder: &Path, path: &str, hash: &str) {
+        let Ok(metadata) = fs::metadata(game_folder.join(path)) else {
+            return;
+        };
+        let Ok(modified) = metadata.modified() else {
+            return;
+        };
+        self.entries.insert(
+            path.to_owned(),
+            IndexEntry {
+                size: metadata.len(),
+                modified,
+                hash: hash.to_owned(),
+            },
+        );
+        self.dirty = true;
+    }
+}
@@ -0,0 +1,51 @@
+#![feature(iterator_try_collect)]
+
+pub mod bundle;
+pub mod compression;
+pub mod endpoint;
+pub mod exclude;
+pub mod history;
+pub mod index;
+pub mod limiter;
+pub mod manifest;
+pub mod mirror;
+pub mod patcher;
+pub mod progress;
+pub mod server;
+pub mod shared_store;
+pub mod staging;
+
+use serde::Serialize;
+use serde_json::Value;
+
+#[derive(Serialize, Clone)]
+pub struct LogPayload {
+    pub level: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub args: Option<Value>,
+}
+
+impl LogPayload {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            level: "error".into(),
+            message: message.into(),
+            args: None,
+        }
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self {
+            level: "warning".into(),
+            message: message.into(),
+            args: None,
+        }
+    }
+
+    /// Attaches Fluent arguments used to format `message` in the frontend.
+    pub fn with_args(mut self, args: Value) -> Self {
+        self.args = Some(args);
+        self
+    }
+}
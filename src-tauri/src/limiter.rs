@@ -0,0 +1,50 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct LimiterInner {
+    /// Bytes per second, 0 for unlimited.
+    limit: AtomicU64,
+    /// When the bytes received so far are paid for.
+    next: Mutex<Instant>,
+}
+
+/// Download rate limit shared by every concurrent patcher download, which can
+/// be changed while a patch is running.
+#[derive(Debug, Clone)]
+pub struct RateLimiter(Arc<LimiterInner>);
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self(Arc::new(LimiterInner {
+            limit: AtomicU64::new(0),
+            next: Mutex::new(Instant::now()),
+        }))
+    }
+}
+
+impl RateLimiter {
+    /// Sets the limit in KB/s, 0 meaning unlimited.
+    pub fn set_limit(&self, limit: u64) {
+        self.0.limit.store(limit * 1024, Ordering::Relaxed);
+    }
+
+    /// Records `bytes` as received and returns how long to wait before
+    /// receiving more.
+    pub fn delay(&self, bytes: u64) -> Duration {
+        let limit = self.0.limit.load(Ordering::Relaxed);
+        let now = Instant::now();
+        let mut next = self.0.next.lock().unwrap();
+        if limit == 0 {
+            *next = now;
+            return Duration::ZERO;
+        }
+        *next = (*next).max(now) + Duration::from_secs_f64(bytes as f64 / limit as f64);
+        next.saturating_duration_since(now)
+    }
+}
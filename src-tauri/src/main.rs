@@ -5,28 +5,39 @@
 #![feature(iterator_try_collect)]
 
 mod config;
-mod endpoint;
-mod patcher;
-mod server;
 mod settings;
 mod store;
 mod user;
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
     path::{self, Path, PathBuf},
     sync::Arc,
 };
 
+use app_lib::{
+    bundle, endpoint, exclude, history, index, limiter, manifest, mirror, patcher, server,
+    shared_store, LogPayload,
+};
+use exclude::Exclusions;
+use history::{History, HistoryEntry};
+use index::HashIndex;
+use limiter::RateLimiter;
 use log::{error, info, warn};
 use mhf_iel::MhfConfig;
+use mirror::{MirrorHealth, Mirrors};
+use patcher::{PatchJob, PatchSource, PatcherOptions};
 use serde::Serialize;
 use serde_json::Value;
 use server::{AuthResponse, JsonRequest, LauncherResponse, MessageData, PatcherResponse};
 use settings::Settings;
+use shared_store::SharedStore;
 use store::StoreHelper;
-use tauri::{async_runtime::Mutex, PhysicalSize};
+use tauri::{
+    async_runtime::{JoinHandle, Mutex},
+    PhysicalSize,
+};
 use tauri::{Manager, Window};
 use tauri_plugin_log::LogTarget;
 use tauri_plugin_store::StoreBuilder;
@@ -34,7 +45,7 @@ use tokio_util::sync::CancellationToken;
 use user::{UserData, UserManager};
 
 use crate::config::{CLASSIC_STYLE, DEFAULT_MESSAGELIST_URL, DEFAULT_SERVERLIST_URL, MODERN_STYLE};
-use crate::endpoint::{Endpoint, EndpointConfig, EndpointVecExt};
+use endpoint::{Endpoint, EndpointConfig, EndpointVecExt};
 
 enum ExitSignal {
     RunGame(u32, bool),
@@ -61,6 +72,9 @@ struct TauriStateSync {
     last_char_id: Option<u32>,
     serverlist_url: String,
     messagelist_url: String,
+    patcher_options: PatcherOptions,
+    mirror_health: MirrorHealth,
+    rate_limiter: RateLimiter,
 
     exit_reason: Option<ExitSignal>,
 
@@ -72,6 +86,8 @@ struct TauriStateSync {
     cancel_launcher: CancellationToken,
     cancel_serverlist: CancellationToken,
     cancel_messagelist: CancellationToken,
+    // Awaited before rolling back, so the two never touch the game folder together.
+    patcher_task: Option<JoinHandle<bool>>,
 }
 
 impl TauriStateSync {
@@ -133,28 +149,6 @@ pub struct AuthPayload {
     has_patch: bool,
 }
 
-#[derive(Serialize, Clone)]
-pub struct LogPayload {
-    level: String,
-    message: String,
-}
-
-impl LogPayload {
-    fn error(message: impl Into<String>) -> Self {
-        Self {
-            level: "error".into(),
-            message: message.into(),
-        }
-    }
-
-    fn warning(message: impl Into<String>) -> Self {
-        Self {
-            level: "warning".into(),
-            message: message.into(),
-        }
-    }
-}
-
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 struct InitialDataPayload {
@@ -172,6 +166,7 @@ struct InitialDataPayload {
     last_char_id: Option<u32>,
     serverlist_url: String,
     messagelist_url: String,
+    patcher_options: PatcherOptions,
     settings: Settings,
 }
 
@@ -194,6 +189,7 @@ async fn initial_data(state: tauri::State<'_, TauriState>) -> Result<InitialData
         last_char_id: state_sync.last_char_id,
         serverlist_url: state_sync.serverlist_url.clone(),
         messagelist_url: state_sync.messagelist_url.clone(),
+        patcher_options: state_sync.patcher_options.clone(),
         settings: settings::get_settings(&state_sync.effective_folder()),
     })
 }
@@ -439,6 +435,139 @@ async fn set_messagelist_url(
     Ok(())
 }
 
+#[tauri::command]
+async fn set_patcher_concurrency(
+    state: tauri::State<'_, TauriState>,
+    concurrency: usize,
+) -> Result<(), String> {
+    let mut state_sync = state.state_sync.lock().await;
+    state_sync.patcher_options.concurrency = concurrency.max(1);
+    let patcher_options = state_sync.patcher_options.clone();
+    state_sync
+        .store
+        .with(|s| s.set("patcher_options", patcher_options));
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_patcher_rate_limit(
+    state: tauri::State<'_, TauriState>,
+    rate_limit: u64,
+) -> Result<(), String> {
+    let mut state_sync = state.state_sync.lock().await;
+    state_sync.patcher_options.rate_limit = rate_limit;
+    // Running patches share the limiter, so this applies to them right away.
+    state_sync.rate_limiter.set_limit(rate_limit);
+    let patcher_options = state_sync.patcher_options.clone();
+    state_sync
+        .store
+        .with(|s| s.set("patcher_options", patcher_options));
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_patcher_shared_store(
+    state: tauri::State<'_, TauriState>,
+    shared_store: Option<PathBuf>,
+    hardlinks: bool,
+) -> Result<(), String> {
+    let mut state_sync = state.state_sync.lock().await;
+    state_sync.patcher_options.shared_store = shared_store;
+    state_sync.patcher_options.shared_store_hardlinks = hardlinks;
+    let patcher_options = state_sync.patcher_options.clone();
+    state_sync
+        .store
+        .with(|s| s.set("patcher_options", patcher_options));
+    Ok(())
+}
+
+#[tauri::command]
+async fn patcher_store_gc(state: tauri::State<'_, TauriState>) -> Result<u64, String> {
+    let state_sync = state.state_sync.lock().await;
+    let Some(root) = state_sync.patcher_options.shared_store.clone() else {
+        return Ok(0);
+    };
+    let game_folders: HashSet<PathBuf> = state_sync
+        .endpoints
+        .iter()
+        .chain(&state_sync.remote_endpoints)
+        .filter_map(|endpoint| endpoint.game_folder.clone())
+        .chain(state_sync.game_folder.clone())
+        .chain([state_sync.effective_folder()])
+        .collect();
+    drop(state_sync);
+    tauri::async_runtime::spawn_blocking(move || {
+        // The hash index of every folder lists the files it holds.
+        let referenced = game_folders
+            .iter()
+            .flat_map(|folder| HashIndex::load(folder).into_hashes())
+            .collect();
+        SharedStore::new(root, false).collect_garbage(&referenced)
+    })
+    .await
+    .map_err(|e| {
+        warn!("shared store garbage collection failed: {}", e);
+        "internal-error"
+    })?
+    .map_err(|e| {
+        warn!("shared store garbage collection failed: {}", e);
+        "file-error".into()
+    })
+}
+
+#[tauri::command]
+async fn get_patcher_exclusions(
+    state: tauri::State<'_, TauriState>,
+) -> Result<Vec<String>, String> {
+    let state_sync = state.state_sync.lock().await;
+    Ok(Exclusions::patterns(&state_sync.effective_folder()))
+}
+
+#[tauri::command]
+async fn set_patcher_exclusions(
+    state: tauri::State<'_, TauriState>,
+    patterns: Vec<String>,
+) -> Result<(), String> {
+    let state_sync = state.state_sync.lock().await;
+    Exclusions::save(&state_sync.effective_folder(), &patterns)?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_patcher_history(
+    state: tauri::State<'_, TauriState>,
+) -> Result<Vec<HistoryEntry>, String> {
+    let state_sync = state.state_sync.lock().await;
+    Ok(History::load(&state_sync.effective_folder())
+        .entries()
+        .to_vec())
+}
+
+#[tauri::command]
+async fn patcher_rollback(state: tauri::State<'_, TauriState>, id: u64) -> Result<(), String> {
+    let mut state_sync = state.state_sync.lock().await;
+    state_sync.cancel_shared.cancel();
+    if let Some(task) = state_sync.patcher_task.take() {
+        if let Err(e) = task.await {
+            warn!("patcher task failed: {}", e);
+        }
+    }
+    let game_folder = state_sync.effective_folder();
+    // Keep the state locked so that no patch starts while rolling back.
+    tauri::async_runtime::spawn_blocking(move || patcher::revert_patches(&game_folder, id))
+        .await
+        .map_err(|e| {
+            warn!("patcher rollback failed: {}", e);
+            "internal-error"
+        })?
+        .map_err(|e| {
+            warn!("patcher rollback failed: {}", e);
+            e.message()
+        })?;
+    drop(state_sync);
+    Ok(())
+}
+
 async fn auth(
     state: tauri::State<'_, TauriState>,
     username: String,
@@ -453,8 +582,9 @@ async fn auth(
             server::patcher_request(
                 &state.client,
                 state_sync.cancel_shared.clone(),
-                &auth_resp.patch_server,
+                &auth_resp.patch_servers(&state_sync.current_endpoint),
                 &patcher::get_etag(&state_sync.effective_folder()),
+                state_sync.current_endpoint.patch_key.as_deref(),
             )
         };
         patcher_req.send().await.map_err(|e| e.into_frontend())?
@@ -704,32 +834,181 @@ async fn export_character(
 
 #[tauri::command]
 async fn patcher_start(window: Window, state: tauri::State<'_, TauriState>) -> Result<(), String> {
-    let (patcher_url, patcher_resp, game_folder, cancel) = {
+    let mut state_sync = state.state_sync.lock().await;
+    state_sync.cancel_shared.cancel();
+    state_sync.cancel_shared = CancellationToken::new();
+    let patch_servers = state_sync
+        .auth_resp_err()?
+        .patch_servers(&state_sync.current_endpoint);
+    let Some(patcher_resp) = state_sync.patcher_resp.take() else {
+        return Err("internal-error".into());
+    };
+    let job = PatchJob {
+        source: PatchSource::Mirrors(Mirrors::new(
+            patch_servers,
+            state_sync.mirror_health.clone(),
+        )),
+        limiter: state_sync.rate_limiter.clone(),
+        patcher_resp,
+        game_folder: state_sync.effective_folder(),
+        options: state_sync.patcher_options.clone(),
+        repair: false,
+        export: None,
+    };
+    let cancel = state_sync.cancel_shared.clone();
+    let task =
+        tauri::async_runtime::spawn(patcher::patch(window, state.client.clone(), job, cancel));
+    state_sync.patcher_task = Some(task);
+    Ok(())
+}
+
+#[tauri::command]
+async fn patcher_repair(window: Window, state: tauri::State<'_, TauriState>) -> Result<(), String> {
+    let (patcher_req, mirrors, limiter, game_folder, patcher_options, cancel) = {
         let mut state_sync = state.state_sync.lock().await;
         state_sync.cancel_shared.cancel();
         state_sync.cancel_shared = CancellationToken::new();
+        let auth_resp = state_sync.auth_resp_err()?;
+        if auth_resp.patch_server.is_empty() {
+            return Err("internal-error".into());
+        }
+        let patch_servers = auth_resp.patch_servers(&state_sync.current_endpoint);
+        // An empty ETag never matches, so the manifest is always sent back.
+        let patcher_req = server::patcher_request(
+            &state.client,
+            state_sync.cancel_shared.clone(),
+            &patch_servers,
+            "",
+            state_sync.current_endpoint.patch_key.as_deref(),
+        );
         (
-            state_sync.auth_resp_err()?.patch_server.clone(),
-            state_sync.patcher_resp.take(),
+            patcher_req,
+            Mirrors::new(patch_servers, state_sync.mirror_health.clone()),
+            state_sync.rate_limiter.clone(),
             state_sync.effective_folder(),
+            state_sync.patcher_options.clone(),
             state_sync.cancel_shared.clone(),
         )
     };
-    let Some(patcher_resp) = patcher_resp else {
+    let Some(patcher_resp) = patcher_req.send().await.map_err(|e| e.into_frontend())? else {
         return Err("internal-error".into());
     };
-    let _client = state.client.clone();
-    tauri::async_runtime::spawn(patcher::patch(
-        window,
-        _client,
-        patcher_url,
+    let job = PatchJob {
+        source: PatchSource::Mirrors(mirrors),
+        limiter,
         patcher_resp,
         game_folder,
-        cancel,
-    ));
+        options: patcher_options,
+        repair: true,
+        export: None,
+    };
+    let mut state_sync = state.state_sync.lock().await;
+    // Another command took over while the manifest was being fetched.
+    if cancel.is_cancelled() {
+        return Ok(());
+    }
+    let task =
+        tauri::async_runtime::spawn(patcher::patch(window, state.client.clone(), job, cancel));
+    state_sync.patcher_task = Some(task);
+    Ok(())
+}
+
+#[tauri::command]
+async fn patcher_import(
+    window: Window,
+    state: tauri::State<'_, TauriState>,
+    path: PathBuf,
+) -> Result<(), String> {
+    let patcher_resp = bundle::read_manifest(&path).map_err(|e| {
+        warn!("failed to read patch bundle: {}", e);
+        e.message()
+    })?;
+    let mut state_sync = state.state_sync.lock().await;
+    if let Some(patch_key) = &state_sync.current_endpoint.patch_key {
+        manifest::verify_signature(
+            patch_key,
+            patcher_resp.content.as_bytes(),
+            patcher_resp.signature.as_deref(),
+        )?;
+    }
+    state_sync.cancel_shared.cancel();
+    state_sync.cancel_shared = CancellationToken::new();
+    let job = PatchJob {
+        source: PatchSource::Bundle(path),
+        limiter: state_sync.rate_limiter.clone(),
+        patcher_resp,
+        game_folder: state_sync.effective_folder(),
+        options: state_sync.patcher_options.clone(),
+        repair: false,
+        export: None,
+    };
+    let cancel = state_sync.cancel_shared.clone();
+    let task =
+        tauri::async_runtime::spawn(patcher::patch(window, state.client.clone(), job, cancel));
+    state_sync.patcher_task = Some(task);
     Ok(())
 }
 
+#[tauri::command]
+async fn patcher_export(
+    window: Window,
+    state: tauri::State<'_, TauriState>,
+    path: PathBuf,
+) -> Result<(), String> {
+    let mut state_sync = state.state_sync.lock().await;
+    // Only the pending patch is exported, which holds the files this machine
+    // is missing, so bundles suit machines on the same version.
+    let patcher_resp = state_sync
+        .patcher_resp
+        .clone()
+        .ok_or("patcher-export-empty")?;
+    state_sync.cancel_shared.cancel();
+    state_sync.cancel_shared = CancellationToken::new();
+    let patch_servers = state_sync
+        .auth_resp_err()?
+        .patch_servers(&state_sync.current_endpoint);
+    let job = PatchJob {
+        source: PatchSource::Mirrors(Mirrors::new(
+            patch_servers,
+            state_sync.mirror_health.clone(),
+        )),
+        limiter: state_sync.rate_limiter.clone(),
+        patcher_resp,
+        game_folder: state_sync.effective_folder(),
+        options: state_sync.patcher_options.clone(),
+        repair: false,
+        export: Some(path),
+    };
+    let cancel = state_sync.cancel_shared.clone();
+    let task =
+        tauri::async_runtime::spawn(patcher::patch(window, state.client.clone(), job, cancel));
+    state_sync.patcher_task = Some(task);
+    Ok(())
+}
+
+#[tauri::command]
+async fn patcher_dry_run(
+    window: Window,
+    state: tauri::State<'_, TauriState>,
+) -> Result<patcher::DryRunReport, String> {
+    let state_sync = state.state_sync.lock().await;
+    let patcher_resp = state_sync
+        .patcher_resp
+        .clone()
+        .ok_or("patcher-changes-empty")?;
+    let game_folder = state_sync.effective_folder();
+    drop(state_sync);
+    tauri::async_runtime::spawn_blocking(move || {
+        patcher::dry_run(&window, &patcher_resp, &game_folder)
+    })
+    .await
+    .map_err(|e| {
+        warn!("patcher dry run failed: {}", e);
+        "internal-error"
+    })?
+    .map_err(|e| e.into())
+}
+
 #[tauri::command]
 async fn patcher_stop(state: tauri::State<'_, TauriState>) -> Result<(), String> {
     let state_sync = state.state_sync.lock().await;
@@ -864,6 +1143,10 @@ fn main() {
                             store::get(&store, "last_char_id", &mut state_sync.last_char_id);
                             store::get(&store, "serverlist_url", &mut state_sync.serverlist_url);
                             store::get(&store, "messagelist_url", &mut state_sync.messagelist_url);
+                            store::get(&store, "patcher_options", &mut state_sync.patcher_options);
+                            state_sync
+                                .rate_limiter
+                                .set_limit(state_sync.patcher_options.rate_limit);
                             state_sync
                                 .remote_endpoints
                                 .apply_config(&state_sync.remote_endpoints_config);
@@ -910,6 +1193,14 @@ fn main() {
                     set_game_folder,
                     set_serverlist_url,
                     set_messagelist_url,
+                    set_patcher_concurrency,
+                    set_patcher_rate_limit,
+                    set_patcher_shared_store,
+                    patcher_store_gc,
+                    get_patcher_exclusions,
+                    set_patcher_exclusions,
+                    get_patcher_history,
+                    patcher_rollback,
                     login,
                     register,
                     create_character,
@@ -917,6 +1208,10 @@ fn main() {
                     delete_character,
                     export_character,
                     patcher_start,
+                    patcher_repair,
+                    patcher_import,
+                    patcher_export,
+                    patcher_dry_run,
                     patcher_stop,
                 ])
                 .build(tauri::generate_context!())
@@ -0,0 +1,347 @@
+use core::fmt;
+use std::collections::HashSet;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, VerifyingKey};
+use log::warn;
+use serde::Deserialize;
+
+use crate::compression::Compression;
+
+/// Newest manifest format understood by the launcher. The legacy `hash\tpath`
+/// list is format 1.
+const FORMAT: u32 = 2;
+
+pub const SIGNATURE_ERROR: &str = "patcher-signature-error";
+pub const UNSAFE_PATH_ERROR: &str = "patcher-unsafe-path-error";
+pub const MANIFEST_ERROR: &str = "patcher-manifest-error";
+pub const MANIFEST_LINE_ERROR: &str = "patcher-manifest-line-error";
+pub const DUPLICATE_PATH_ERROR: &str = "patcher-duplicate-path-error";
+
+/// Device names Windows reserves in every folder, with or without extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+/// Prefix of the files the launcher keeps in the game folder.
+const LAUNCHER_PREFIX: &str = "patcher.";
+
+#[derive(Debug)]
+pub enum ManifestError {
+    /// A line that could not be parsed, counting from 1.
+    Syntax { line: usize, detail: String },
+    /// A format newer than the launcher understands.
+    UnsupportedFormat(u32),
+    /// A path that escapes the game folder or names a reserved file.
+    UnsafePath(String),
+    /// A path listed more than once. Windows paths are case-insensitive, so
+    /// `a.pac` and `A.pac` are the same file.
+    DuplicatePath(String),
+}
+
+impl ManifestError {
+    /// Fluent message describing the error to the user, without arguments.
+    pub fn message(&self) -> &'static str {
+        MANIFEST_ERROR
+    }
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Syntax { line, detail } => {
+                write!(f, "invalid manifest line {}: {}", line, detail)
+            }
+            Self::UnsupportedFormat(format) => write!(f, "unsupported manifest format {}", format),
+            Self::UnsafePath(path) => write!(f, "unsafe manifest path {:?}", path),
+            Self::DuplicatePath(path) => write!(f, "duplicate manifest path {:?}", path),
+        }
+    }
+}
+
+fn is_safe_component(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or_default();
+    component != ".."
+        && !component.ends_with(['.', ' '])
+        && !component
+            .chars()
+            .any(|c| c.is_control() || matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
+        && !RESERVED_NAMES
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(stem.trim_end()))
+}
+
+/// Turns a manifest path into a relative `a/b/c` path, rejecting any that
+/// could point outside of the game folder, such as `../x`, `C:/x`, `\\?\x` or
+/// `//server/share`, at reserved device names, or at the launcher's own files.
+fn normalize_path(path: &str) -> Result<String, ManifestError> {
+    let normalized = path.replace('\\', "/");
+    // A single leading slash is how legacy manifests spell relative paths,
+    // two make it a UNC path.
+    let components: Vec<_> = normalized
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    let is_safe = !normalized.starts_with("//")
+        && !components.is_empty()
+        && components.iter().copied().all(is_safe_component)
+        && !components[0]
+            .to_ascii_lowercase()
+            .starts_with(LAUNCHER_PREFIX);
+    if !is_safe {
+        warn!("unsafe path in patcher manifest: {:?}", path);
+        return Err(ManifestError::UnsafePath(path.to_owned()));
+    }
+    Ok(components.join("/"))
+}
+
+/// Decodes a base64 Ed25519 public key, as configured on an endpoint.
+pub fn parse_key(key: &str) -> Option<VerifyingKey> {
+    let bytes = STANDARD.decode(key.trim()).ok()?;
+    VerifyingKey::from_bytes(bytes.as_slice().try_into().ok()?).ok()
+}
+
+/// Checks the base64 detached `signature` of a manifest body against `key`.
+pub fn verify_signature(
+    key: &str,
+    content: &[u8],
+    signature: Option<&str>,
+) -> Result<(), &'static str> {
+    let key = parse_key(key).ok_or(SIGNATURE_ERROR)?;
+    let Some(signature) = signature else {
+        warn!("patcher manifest is not signed");
+        return Err(SIGNATURE_ERROR);
+    };
+    let signature = STANDARD
+        .decode(signature.trim())
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or(SIGNATURE_ERROR)?;
+    key.verify_strict(content, &signature).map_err(|e| {
+        warn!("patcher manifest signature is invalid: {}", e);
+        SIGNATURE_ERROR
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestFile {
+    pub path: String,
+    pub hash: String,
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// Optional files are kept up to date, but never downloaded if missing.
+    #[serde(default)]
+    pub optional: bool,
+    /// Set if the server stores the file compressed, in which case `size` and
+    /// `hash` still describe the decompressed file.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    #[serde(default)]
+    pub compressed_size: Option<u64>,
+    /// Deltas that rebuild the file from older versions, tried before
+    /// downloading it in full.
+    #[serde(default)]
+    pub deltas: Vec<ManifestDelta>,
+}
+
+/// Binary delta from an older version of a file to the one in the manifest.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestDelta {
+    /// Hash of the version the delta applies to.
+    pub from: String,
+    /// Path of the delta on the patch server.
+    pub path: String,
+    pub size: u64,
+}
+
+impl ManifestFile {
+    /// Number of bytes transferred when downloading the file, if known.
+    pub fn download_size(&self) -> Option<u64> {
+        match self.compression {
+            Some(_) => self.compressed_size,
+            None => self.size,
+        }
+    }
+}
+
+/// Patch manifest served from `{patch_server}/check`.
+///
+/// Besides the legacy list of `hash\tpath` lines, servers can send a JSON
+/// object:
+///
+/// ```json
+/// {
+///   "format": 2,
+///   "label": "2024-01-01",
+///   "files": [
+///     { "path": "dat/file.pac", "hash": "<sha256>", "size": 1024, "optional": false },
+///     { "path": "dat/big.pac", "hash": "<sha256>", "size": 4096, "compression": "zstd", "compressedSize": 512 },
+///     {
+///       "path": "dat/huge.pac", "hash": "<sha256>", "size": 65536,
+///       "deltas": [{ "from": "<old sha256>", "path": "deltas/huge.pac.zst", "size": 128 }]
+///     }
+///   ],
+///   "delete": ["dat/old.pac"]
+/// }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub format: u32,
+    #[serde(default)]
+    pub label: Option<String>,
+    pub files: Vec<ManifestFile>,
+    #[serde(default)]
+    pub delete: Vec<String>,
+}
+
+impl Manifest {
+    pub fn parse(content: &str) -> Result<Self, ManifestError> {
+        let mut manifest = if content.trim_start().starts_with('{') {
+            let manifest: Self =
+                serde_json::from_str(content).map_err(|e| ManifestError::Syntax {
+                    line: e.line(),
+                    detail: e.to_string(),
+                })?;
+            if manifest.format > FORMAT {
+                return Err(ManifestError::UnsupportedFormat(manifest.format));
+            }
+            manifest
+        } else {
+            Self::parse_legacy(content)?
+        };
+        let mut paths = HashSet::new();
+        for file in &mut manifest.files {
+            file.path = normalize_path(&file.path)?;
+            if !paths.insert(file.path.to_lowercase()) {
+                warn!("duplicate path in patcher manifest: {:?}", file.path);
+                return Err(ManifestError::DuplicatePath(file.path.clone()));
+            }
+            // Hashes of local files are lowercase hex.
+            file.hash.make_ascii_lowercase();
+            for delta in &mut file.deltas {
+                delta.path = delta.path.trim_start_matches('/').to_owned();
+                delta.from.make_ascii_lowercase();
+            }
+        }
+        // A file both replaced and deleted, or deleted twice, would be backed
+        // up over its own backup.
+        for path in &mut manifest.delete {
+            *path = normalize_path(path)?;
+            if !paths.insert(path.to_lowercase()) {
+                warn!("duplicate path in patcher manifest: {:?}", path);
+                return Err(ManifestError::DuplicatePath(path.clone()));
+            }
+        }
+        Ok(manifest)
+    }
+
+    fn parse_legacy(content: &str) -> Result<Self, ManifestError> {
+        let files = content
+            .lines()
+            .enumerate()
+            .map(|(i, line)| {
+                let (hash, path) = line.split_once('\t').ok_or_else(|| ManifestError::Syntax {
+                    line: i + 1,
+                    detail: format!("expected `hash\\tpath`, got {:?}", line),
+                })?;
+                Ok(ManifestFile {
+                    path: path.to_owned(),
+                    hash: hash.to_owned(),
+                    size: None,
+                    optional: false,
+                    compression: None,
+                    compressed_size: None,
+                    deltas: Vec::new(),
+                })
+            })
+            .try_collect()?;
+        Ok(Self {
+            format: 1,
+            files,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_paths_outside_game_folder() {
+        for path in [
+            "../x",
+            "a/../../x",
+            "C:/x",
+            "C:x",
+            "\\\\?\\x",
+            "//server/share",
+        ] {
+            assert!(normalize_path(path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_names() {
+        for path in [
+            "CON",
+            "dat/con.txt",
+            "aux .pac",
+            "x.",
+            "x ",
+            "file:stream",
+            "Patcher.etag",
+        ] {
+            assert!(normalize_path(path).is_err(), "{:?}", path);
+        }
+        assert!(!is_safe_component("con.txt"));
+        assert!(!is_safe_component("aux .pac"));
+    }
+
+    #[test]
+    fn rejects_duplicate_paths() {
+        let hash = "0".repeat(64);
+        for content in [
+            format!("{0}\tdat/a.pac\n{0}\tDAT/A.pac", hash),
+            r#"{"format":2,"files":[],"delete":["a.pac","/A.pac"]}"#.to_owned(),
+            format!(
+                r#"{{"format":2,"files":[{{"path":"a.pac","hash":"{}"}}],"delete":["a.pac"]}}"#,
+                hash
+            ),
+        ] {
+            assert!(
+                matches!(
+                    Manifest::parse(&content),
+                    Err(ManifestError::DuplicatePath(_))
+                ),
+                "{}",
+                content
+            );
+        }
+    }
+
+    #[test]
+    fn lowercases_hashes() {
+        let content = format!(
+            r#"{{"format":2,"files":[{{"path":"a.pac","hash":"{}","deltas":[{{"from":"{}","path":"a.delta","size":1}}]}}]}}"#,
+            "AB".repeat(32),
+            "CD".repeat(32)
+        );
+        let manifest = Manifest::parse(&content).unwrap();
+        assert_eq!(manifest.files[0].hash, "ab".repeat(32));
+        assert_eq!(manifest.files[0].deltas[0].from, "cd".repeat(32));
+    }
+
+    #[test]
+    fn normalizes_relative_paths() {
+        assert_eq!(normalize_path("/dat/x.pac").unwrap(), "dat/x.pac");
+        assert_eq!(normalize_path("dat\\x.pac").unwrap(), "dat/x.pac");
+        assert_eq!(normalize_path("./dat//x.pac").unwrap(), "dat/x.pac");
+        assert_eq!(
+            normalize_path("dat/patcher.etag").unwrap(),
+            "dat/patcher.etag"
+        );
+    }
+}
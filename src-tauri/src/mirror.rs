@@ -0,0 +1,83 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Weight of the latest download when smoothing the speed of a mirror.
+const SPEED_SMOOTHING: f64 = 0.3;
+/// Smaller downloads finish too quickly to say anything about a mirror's speed.
+const MIN_SPEED_SAMPLE: u64 = 256 * 1024;
+
+#[derive(Debug, Default)]
+struct MirrorStats {
+    /// Bytes per second, 0 until measured.
+    speed: f64,
+    failed: bool,
+}
+
+/// Health and speed of every patch server seen this session, shared between
+/// patcher runs so that later patches start on the fastest working mirror.
+#[derive(Debug, Default, Clone)]
+pub struct MirrorHealth(Arc<Mutex<HashMap<String, MirrorStats>>>);
+
+/// Ordered patch server URLs for a single patch, the primary server first.
+#[derive(Debug, Clone)]
+pub struct Mirrors {
+    urls: Vec<String>,
+    health: MirrorHealth,
+}
+
+impl Mirrors {
+    pub fn new(urls: Vec<String>, health: MirrorHealth) -> Self {
+        Self { urls, health }
+    }
+
+    /// Returns the URLs in the order they should be tried: the fastest healthy
+    /// mirror, the other healthy ones as configured, then the ones that failed.
+    pub fn order(&self) -> Vec<&str> {
+        let stats = self.health.0.lock().unwrap();
+        let healthy = |url: &&String| !stats.get(*url).is_some_and(|s| s.failed);
+        let speed = |url: &&String| stats.get(*url).map_or(0.0, |s| s.speed);
+        let fastest = self
+            .urls
+            .iter()
+            .filter(healthy)
+            .filter(|url| speed(url) > 0.0)
+            .max_by(|a, b| speed(a).total_cmp(&speed(b)));
+        let mut ordered: Vec<&str> = fastest.iter().map(|url| url.as_str()).collect();
+        ordered.extend(
+            self.urls
+                .iter()
+                .filter(|url| healthy(url) && Some(*url) != fastest)
+                .map(String::as_str),
+        );
+        ordered.extend(
+            self.urls
+                .iter()
+                .filter(|url| !healthy(url))
+                .map(String::as_str),
+        );
+        ordered
+    }
+
+    pub fn record_success(&self, url: &str, bytes: u64, elapsed: Duration) {
+        let mut stats = self.health.0.lock().unwrap();
+        let stats = stats.entry(url.to_owned()).or_default();
+        stats.failed = false;
+        if bytes < MIN_SPEED_SAMPLE || elapsed.is_zero() {
+            return;
+        }
+        let sample = bytes as f64 / elapsed.as_secs_f64();
+        stats.speed = if stats.speed == 0.0 {
+            sample
+        } else {
+            stats.speed + (sample - stats.speed) * SPEED_SMOOTHING
+        };
+    }
+
+    pub fn record_failure(&self, url: &str) {
+        let mut stats = self.health.0.lock().unwrap();
+        stats.entry(url.to_owned()).or_default().failed = true;
+    }
+}
@@ -1,228 +1,1511 @@
+use core::fmt;
 use std::{
+    collections::HashMap,
     fs,
-    io::{self, Write},
+    io::{self, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
 };
 
+use futures_util::{stream, StreamExt};
 use log::{info, warn};
-use serde::Serialize;
-use serde_repr::Serialize_repr;
-use sha2::Digest;
-use tauri::Window;
-use tokio::select;
+use rand::Rng;
+use reqwest::{
+    header::{RANGE, RETRY_AFTER},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{select, sync::Semaphore};
 use tokio_util::sync::CancellationToken;
 
-use crate::{server::PatcherResponse, LogPayload};
+use crate::{
+    bundle,
+    compression::{self, Compression, Decoder},
+    exclude::Exclusions,
+    history::{self, Change, History, HistoryFile},
+    index::{self, HashIndex},
+    limiter::RateLimiter,
+    manifest::{
+        Manifest, ManifestDelta, ManifestError, ManifestFile, DUPLICATE_PATH_ERROR,
+        MANIFEST_LINE_ERROR, UNSAFE_PATH_ERROR,
+    },
+    mirror::Mirrors,
+    progress::{self, Progress, ProgressSink, State},
+    server::PatcherResponse,
+    shared_store::SharedStore,
+    staging::{self, Staging},
+    LogPayload,
+};
 
 pub const NETWORK_ERROR: &str = "patcher-network-error";
 pub const FILE_ERROR: &str = "patcher-file-error";
+const HASH_ERROR: &str = "patcher-hash-error";
+const DISK_SPACE_ERROR: &str = "patcher-disk-space-error";
+const DOWNLOAD_ERROR: &str = "patcher-download-error";
+const FILE_PATH_ERROR: &str = "patcher-file-path-error";
 
 const ETAG_FILE: &str = "patcher.etag";
+const RESUME_FILE: &str = "patcher.resume";
+const JOURNAL_FILE: &str = "patcher.journal";
+const JOURNAL_PARTIAL_FILE: &str = "patcher.journal.partial";
+const BACKUP_FOLDER: &str = "patcher.backup";
+const DELTA_SUFFIX: &str = ".delta";
+// Deltas are applied with the whole reference file in memory, so larger files
+// are downloaded in full instead.
+const MAX_DELTA_REFERENCE_SIZE: u64 = 256 * 1024 * 1024;
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_CONCURRENCY: usize = 4;
+const HASH_ATTEMPTS: usize = 3;
+const DOWNLOAD_RETRIES: usize = 5;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(60);
 
-#[derive(Debug, Serialize_repr, Clone)]
-#[repr(u8)]
-enum State {
-    Checking,
-    Downloading,
-    Patching,
-    Done,
-    Error,
-}
-
-#[derive(Debug, Clone, Serialize)]
-struct PatcherEvent {
-    total: usize,
-    current: usize,
-    state: State,
-}
-
-fn send_event(window: &Window, total: usize, current: usize, state: State) {
-    window
-        .emit(
-            "patcher",
-            PatcherEvent {
-                total,
-                current,
-                state,
-            },
-        )
-        .unwrap_or_else(|e| warn!("failed to emit message: {}", e));
-}
-
-fn send_error(window: &Window, msg: &str) {
-    warn!("patcher error: {}", msg);
-    window
-        .emit("log", LogPayload::error(msg))
-        .unwrap_or_else(|e| warn!("failed to emit message: {}", e));
-    window
-        .emit(
-            "patcher",
-            PatcherEvent {
-                total: 0,
-                current: 0,
-                state: State::Error,
-            },
-        )
-        .unwrap_or_else(|e| warn!("failed to emit message: {}", e));
+/// User-configurable patcher behaviour, persisted in the launcher config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PatcherOptions {
+    pub concurrency: usize,
+    /// Download limit in KB/s, 0 for unlimited.
+    pub rate_limit: u64,
+    /// Folder of the content-addressed store shared between game folders, if
+    /// enabled.
+    pub shared_store: Option<PathBuf>,
+    pub shared_store_hardlinks: bool,
+}
+
+impl Default for PatcherOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_CONCURRENCY,
+            rate_limit: 0,
+            shared_store: None,
+            shared_store_hardlinks: false,
+        }
+    }
+}
+
+/// A single patcher run against the manifest in `patcher_resp`.
+pub struct PatchJob {
+    pub source: PatchSource,
+    pub limiter: RateLimiter,
+    pub patcher_resp: PatcherResponse,
+    pub game_folder: PathBuf,
+    pub options: PatcherOptions,
+    /// Rehashes every file instead of trusting the hash index, so that files
+    /// corrupted without changing their size or modification time are found.
+    pub repair: bool,
+    /// Writes the staged files into an offline bundle at this path instead of
+    /// applying them.
+    pub export: Option<PathBuf>,
+}
+
+/// Where the files of a patch come from.
+pub enum PatchSource {
+    Mirrors(Mirrors),
+    /// An offline bundle, see `bundle::Bundle`.
+    Bundle(PathBuf),
+}
+
+/// Shared state of the downloads of a single patch.
+struct Downloader<'a> {
+    client: &'a reqwest::Client,
+    game_folder: &'a Path,
+    mirrors: &'a Mirrors,
+    limiter: &'a RateLimiter,
+    store: Option<&'a SharedStore>,
+    progress: &'a Progress<'a>,
+    cancel: &'a CancellationToken,
+    rebuilds: Semaphore,
+}
+
+/// Download progress of a single staged file, persisted so that an interrupted
+/// patch can continue where it stopped instead of starting from scratch.
+///
+/// Entries are updated in place while their file downloads, so that the
+/// periodic save also records the files that are still in flight.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ResumeEntry {
+    hash: String,
+    offset: AtomicU64,
+    complete: AtomicBool,
 }
 
+impl ResumeEntry {
+    fn new(hash: &str) -> Self {
+        Self {
+            hash: hash.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn offset(&self) -> u64 {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    fn set_offset(&self, offset: u64) {
+        self.offset.store(offset, Ordering::Relaxed);
+    }
+
+    fn is_complete(&self) -> bool {
+        self.complete.load(Ordering::Relaxed)
+    }
+
+    fn set_complete(&self, complete: bool) {
+        self.complete.store(complete, Ordering::Relaxed);
+    }
+
+    /// Forgets the staged bytes, which have to be downloaded again.
+    fn reset(&self) {
+        self.set_offset(0);
+        self.set_complete(false);
+    }
+}
+
+/// Record of an in-progress apply, written to the game folder before any file is
+/// replaced so that a crash halfway through can be rolled back on the next run.
+#[derive(Debug, Serialize, Deserialize)]
+struct Journal {
+    patcher_folder: PathBuf,
+    entries: Vec<JournalEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    path: String,
+    existed: bool,
+    #[serde(default)]
+    delete: bool,
+}
+
+/// Errors that abort a patch, reported to the frontend as a Fluent message and
+/// logged in full.
+#[derive(Debug)]
+pub enum PatchError {
+    Message(&'static str),
+    /// A file operation on `path` failed.
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    DiskSpace {
+        required: u64,
+        available: u64,
+    },
+    /// Every mirror failed to send the file, the last one with HTTP `status`
+    /// if it answered at all.
+    Download {
+        path: String,
+        retries: usize,
+        status: Option<u16>,
+    },
+    Hash {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    Manifest(ManifestError),
+}
+
+impl From<&'static str> for PatchError {
+    fn from(msg: &'static str) -> Self {
+        Self::Message(msg)
+    }
+}
+
+impl From<ManifestError> for PatchError {
+    fn from(err: ManifestError) -> Self {
+        Self::Manifest(err)
+    }
+}
+
+impl PatchError {
+    pub(crate) fn io(path: &Path) -> impl FnOnce(io::Error) -> Self + '_ {
+        move |source| Self::Io {
+            path: path.to_owned(),
+            source,
+        }
+    }
+
+    /// Fluent message describing the error, for commands that report errors
+    /// without arguments.
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::Message(msg) => msg,
+            Self::Io { .. } | Self::DiskSpace { .. } | Self::Hash { .. } => FILE_ERROR,
+            Self::Download { .. } => NETWORK_ERROR,
+            Self::Manifest(err) => err.message(),
+        }
+    }
+
+    /// Logs why a blocking task working on `path` failed to complete, usually
+    /// a panic.
+    fn join(path: &Path) -> impl FnOnce(tokio::task::JoinError) -> Self + '_ {
+        move |e| {
+            warn!("patcher task failed: {}", e);
+            Self::io(path)(io::Error::other(e))
+        }
+    }
+
+    fn into_log(self) -> LogPayload {
+        // Only logs can name the file or line, the messages returned by
+        // commands don't take any arguments.
+        let log = LogPayload::error(match &self {
+            Self::Io { .. } => FILE_PATH_ERROR,
+            Self::DiskSpace { .. } => DISK_SPACE_ERROR,
+            Self::Download { .. } => DOWNLOAD_ERROR,
+            Self::Hash { .. } => HASH_ERROR,
+            Self::Manifest(ManifestError::Syntax { .. }) => MANIFEST_LINE_ERROR,
+            Self::Manifest(ManifestError::UnsafePath(_)) => UNSAFE_PATH_ERROR,
+            Self::Manifest(ManifestError::DuplicatePath(_)) => DUPLICATE_PATH_ERROR,
+            _ => self.message(),
+        });
+        let args = match &self {
+            Self::Message(_) | Self::Manifest(ManifestError::UnsupportedFormat(_)) => None,
+            Self::Io { path, source } => Some(json!({
+                "path": path,
+                "error": source.kind().to_string(),
+            })),
+            Self::DiskSpace {
+                required,
+                available,
+            } => Some(json!({
+                "required": required.div_ceil(1024 * 1024),
+                "available": available / (1024 * 1024),
+            })),
+            Self::Download {
+                path,
+                retries,
+                status,
+            } => Some(json!({
+                "path": path,
+                "retries": retries,
+                "status": status.map_or("none".into(), |status| status.to_string()),
+            })),
+            Self::Hash { path, .. } => Some(json!({ "path": path })),
+            Self::Manifest(ManifestError::Syntax { line, .. }) => Some(json!({ "line": line })),
+            Self::Manifest(
+                ManifestError::UnsafePath(path) | ManifestError::DuplicatePath(path),
+            ) => Some(json!({ "path": path })),
+        };
+        match args {
+            Some(args) => log.with_args(args),
+            None => log,
+        }
+    }
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Message(msg) => write!(f, "{}", msg),
+            Self::Io { path, source } => write!(f, "{:?}: {}", path, source),
+            Self::DiskSpace {
+                required,
+                available,
+            } => write!(
+                f,
+                "{} bytes required, {} bytes available",
+                required, available
+            ),
+            Self::Download {
+                path,
+                retries,
+                status,
+            } => {
+                write!(f, "failed to download {} after {} retries", path, retries)?;
+                match status {
+                    Some(status) => write!(f, " (HTTP {})", status),
+                    None => Ok(()),
+                }
+            }
+            Self::Hash {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "hash mismatch for {}: expected {}, got {}",
+                path, expected, actual
+            ),
+            Self::Manifest(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Failure of a single download attempt.
+#[derive(Debug)]
+enum DownloadError {
+    /// Connection problems and server errors that may go away on their own,
+    /// with the HTTP status if the server answered and the delay it asked for
+    /// in `Retry-After`.
+    Transient {
+        status: Option<u16>,
+        retry_after: Option<Duration>,
+    },
+    /// The server refused to send the file, or sent one that could not be
+    /// decompressed.
+    Rejected {
+        status: Option<u16>,
+    },
+    Fatal(PatchError),
+}
+
+impl From<&'static str> for DownloadError {
+    fn from(msg: &'static str) -> Self {
+        Self::Fatal(msg.into())
+    }
+}
+
+impl From<PatchError> for DownloadError {
+    fn from(err: PatchError) -> Self {
+        Self::Fatal(err)
+    }
+}
+
+fn send_error(sink: &dyn ProgressSink, err: PatchError) {
+    warn!("patcher error: {}", err);
+    sink.send_log(err.into_log());
+    progress::send_state(sink, State::Error);
+}
+
+/// Returns the manifest files that differ from the game folder, split into
+/// the ones to patch and the ones left outdated because they are excluded.
 fn get_changed_paths<'a>(
-    patcher_content: &'a str,
+    manifest: &'a Manifest,
     game_folder: &Path,
-) -> Result<Vec<&'a str>, &'static str> {
-    patcher_content
-        .lines()
-        .filter_map(|line| {
-            let Some((patcher_hash, mut patcher_path)) = line.split_once('\t') else {
-                return Some(Err(NETWORK_ERROR));
-            };
-            patcher_path = patcher_path.trim_start_matches('/');
-            let client_path = game_folder.join(patcher_path);
+    index: &mut HashIndex,
+    exclusions: &Exclusions,
+    progress: &Progress,
+) -> (Vec<&'a ManifestFile>, Vec<&'a ManifestFile>) {
+    manifest
+        .files
+        .iter()
+        .filter(|manifest_file| {
+            let client_path = game_folder.join(&manifest_file.path);
 
             info!(
                 "files: {} {} {}",
                 game_folder.to_str().unwrap(),
-                &patcher_path,
+                &manifest_file.path,
                 &client_path.to_str().unwrap()
             );
 
-            if let Ok(mut file) = fs::File::open(&client_path) {
-                let mut hasher = sha2::Sha256::new();
-                if io::copy(&mut file, &mut hasher).is_ok() {
-                    let client_hash = format!("{:x}", hasher.finalize());
-                    info!("hashes: {} {}", patcher_hash, client_hash);
-                    if patcher_hash == client_hash {
-                        return None;
+            let size = fs::metadata(&client_path).map_or(0, |m| m.len());
+            progress.add_size(size);
+            let client_hash = index.hash(game_folder, &manifest_file.path);
+            progress.add_bytes(size);
+            progress.complete_file();
+            match client_hash {
+                Ok(client_hash) => {
+                    info!("hashes: {} {}", manifest_file.hash, client_hash);
+                    manifest_file.hash != client_hash
+                }
+                Err(_) => !manifest_file.optional,
+            }
+        })
+        .partition(|manifest_file| !exclusions.is_excluded(&manifest_file.path))
+}
+
+/// Outcome of the `Checking` phase of a patch.
+struct Changes<'a> {
+    changed: Vec<&'a ManifestFile>,
+    deleted: Vec<&'a str>,
+    /// Outdated files left alone because they are excluded.
+    excluded: Vec<String>,
+}
+
+fn check_changes<'a>(
+    sink: &dyn ProgressSink,
+    manifest: &'a Manifest,
+    game_folder: &Path,
+    index: &mut HashIndex,
+) -> Result<Changes<'a>, PatchError> {
+    let exclusions = Exclusions::load(game_folder)?;
+    let checking = Progress::new(sink, State::Checking, manifest.files.len());
+    let (changed, excluded_paths) =
+        get_changed_paths(manifest, game_folder, index, &exclusions, &checking);
+    index.save(game_folder);
+    let (deleted, excluded_deletes): (Vec<_>, Vec<_>) = manifest
+        .delete
+        .iter()
+        .map(String::as_str)
+        .filter(|path| game_folder.join(path).exists())
+        .partition(|path| !exclusions.is_excluded(path));
+    let excluded = excluded_paths
+        .iter()
+        .map(|p| p.path.as_str())
+        .chain(excluded_deletes)
+        .map(str::to_owned)
+        .collect();
+    Ok(Changes {
+        changed,
+        deleted,
+        excluded,
+    })
+}
+
+fn load_resume(patcher_folder: &Path) -> HashMap<String, ResumeEntry> {
+    fs::read(patcher_folder.join(RESUME_FILE))
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_resume(patcher_folder: &Path, resume: &HashMap<String, ResumeEntry>) {
+    let result = serde_json::to_vec(resume)
+        .map_err(io::Error::from)
+        .and_then(|data| fs::write(patcher_folder.join(RESUME_FILE), data));
+    if let Err(e) = result {
+        warn!("failed to save patcher resume state: {}", e);
+    }
+}
+
+async fn send_range(
+    client: &reqwest::Client,
+    url: &str,
+    offset: u64,
+    cancel: &CancellationToken,
+) -> Result<Option<reqwest::Response>, DownloadError> {
+    let mut req = client.get(url);
+    if offset > 0 {
+        req = req.header(RANGE, format!("bytes={}-", offset));
+    }
+    select! {
+        _ = cancel.cancelled() => Ok(None),
+        resp = req.send() => resp.map(Some).map_err(|e| {
+            warn!("failed to request {}: {}", url, e);
+            DownloadError::Transient { status: None, retry_after: None }
+        }),
+    }
+}
+
+fn check_status(resp: reqwest::Response) -> Result<reqwest::Response, DownloadError> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    warn!("failed to download {}: {}", resp.url(), status);
+    let code = Some(status.as_u16());
+    if status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+    {
+        let retry_after = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse().ok())
+            .map(Duration::from_secs);
+        Err(DownloadError::Transient {
+            status: code,
+            retry_after,
+        })
+    } else {
+        Err(DownloadError::Rejected { status: code })
+    }
+}
+
+/// Delay before the `retry`th retry of a download, doubling every time and
+/// randomized so that parallel downloads don't retry in lockstep.
+fn retry_delay(retry: usize) -> Duration {
+    let delay = RETRY_BASE_DELAY
+        .saturating_mul(1 << (retry - 1).min(16))
+        .min(RETRY_MAX_DELAY);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+/// Downloads `url` into `path`, continuing from the offset of `entry` when the
+/// server honours range requests, and decompressing it on the fly if
+/// `compression` is set. Returns `false` if the download was cancelled.
+async fn download_file(
+    dl: &Downloader<'_>,
+    url: &str,
+    path: &Path,
+    entry: &ResumeEntry,
+    size_known: bool,
+    compression: Option<Compression>,
+) -> Result<bool, DownloadError> {
+    let parent = parent_folder(path)?;
+    fs::create_dir_all(parent).map_err(PatchError::io(parent))?;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(PatchError::io(path))?;
+    let len = file.metadata().map_err(PatchError::io(path))?.len();
+    let mut offset = entry.offset().min(len);
+    let mut decoder = match compression {
+        // The decoder state is lost between attempts, so compressed files are
+        // always downloaded from the start.
+        Some(compression) => {
+            offset = 0;
+            Some(Decoder::new(compression).map_err(PatchError::io(path))?)
+        }
+        None => None,
+    };
+
+    let Some(mut resp) = send_range(dl.client, url, offset, dl.cancel).await? else {
+        return Ok(false);
+    };
+    if offset > 0 && resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        offset = 0;
+        let Some(full_resp) = send_range(dl.client, url, 0, dl.cancel).await? else {
+            return Ok(false);
+        };
+        resp = full_resp;
+    }
+    if resp.status() == StatusCode::PARTIAL_CONTENT {
+        info!("resuming download of {} at byte {}", url, offset);
+    } else {
+        resp = check_status(resp)?;
+        offset = 0;
+    }
+    let learned_size = resp
+        .content_length()
+        .filter(|_| !size_known)
+        .map(|len| offset + len);
+    if let Some(size) = learned_size {
+        dl.progress.add_size(size);
+    }
+    dl.progress.skip_bytes(offset);
+    file.set_len(offset).map_err(PatchError::io(path))?;
+    entry.set_offset(offset);
+    file.seek(SeekFrom::Start(offset))
+        .map_err(PatchError::io(path))?;
+
+    while let Some(chunk) = select! {
+        _ = dl.cancel.cancelled() => return Ok(false),
+        chunk = resp.chunk() => chunk.map_err(|e| {
+            warn!("failed to download {}: {}", url, e);
+            // The next attempt reports the bytes it continues from again.
+            dl.progress.reset_file(offset, learned_size);
+            DownloadError::Transient { status: None, retry_after: None }
+        })?
+    } {
+        match &mut decoder {
+            Some(decoder) => {
+                let data = decoder.decode(&chunk).map_err(|e| {
+                    warn!("failed to decompress {}: {}", url, e);
+                    dl.progress.reset_file(offset, learned_size);
+                    DownloadError::Rejected { status: None }
+                })?;
+                file.write_all(&data).map_err(PatchError::io(path))?;
+            }
+            None => file.write_all(&chunk).map_err(PatchError::io(path))?,
+        }
+        offset += chunk.len() as u64;
+        entry.set_offset(offset);
+        dl.progress.add_bytes(chunk.len() as u64);
+        let delay = dl.limiter.delay(chunk.len() as u64);
+        if !delay.is_zero() {
+            select! {
+                _ = dl.cancel.cancelled() => return Ok(false),
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+    if let Some(decoder) = decoder {
+        let data = decoder.finish().map_err(|e| {
+            warn!("failed to decompress {}: {}", url, e);
+            dl.progress.reset_file(offset, learned_size);
+            DownloadError::Rejected { status: None }
+        })?;
+        file.write_all(&data).map_err(PatchError::io(path))?;
+    }
+    entry.set_complete(true);
+    Ok(true)
+}
+
+/// Applies a downloaded delta to `reference` and checks the rebuilt file
+/// against the manifest.
+fn rebuild_file(
+    reference: &Path,
+    delta_path: &Path,
+    patcher_path: &Path,
+    changed_path: &ManifestFile,
+) -> Result<(), PatchError> {
+    let applied = compression::apply_delta(reference, delta_path, patcher_path);
+    fs::remove_file(delta_path).unwrap_or_else(|e| warn!("failed to remove delta: {}", e));
+    applied.map_err(PatchError::io(patcher_path))?;
+    let staged_hash = index::hash_file(patcher_path).map_err(PatchError::io(patcher_path))?;
+    if staged_hash != changed_path.hash {
+        return Err(PatchError::Hash {
+            path: changed_path.path.clone(),
+            expected: changed_path.hash.clone(),
+            actual: staged_hash,
+        });
+    }
+    Ok(())
+}
+
+/// Rebuilds a changed file in `patcher_folder` from its current version in the
+/// game folder and a downloaded `delta`. Returns `false` if cancelled, while
+/// any error means the file has to be downloaded in full instead.
+async fn apply_delta(
+    dl: &Downloader<'_>,
+    changed_path: &ManifestFile,
+    delta: &ManifestDelta,
+    patcher_folder: &Path,
+) -> Result<bool, PatchError> {
+    let delta_path = patcher_folder.join(format!("{}{}", changed_path.path, DELTA_SUFFIX));
+    let entry = ResumeEntry::default();
+    let mut last_status = None;
+    let mut downloaded = false;
+    for mirror in dl.mirrors.order() {
+        let url = format!("{}/{}", mirror, delta.path);
+        match download_file(dl, &url, &delta_path, &entry, true, None).await {
+            Ok(false) => return Ok(false),
+            Ok(true) => {
+                downloaded = true;
+                break;
+            }
+            Err(DownloadError::Fatal(err)) => return Err(err),
+            Err(DownloadError::Transient { status, .. } | DownloadError::Rejected { status }) => {
+                warn!("failed to download delta {} from {}", delta.path, mirror);
+                last_status = status.or(last_status);
+            }
+        }
+    }
+    if !downloaded {
+        return Err(PatchError::Download {
+            path: delta.path.clone(),
+            retries: 0,
+            status: last_status,
+        });
+    }
+    let reference = dl.game_folder.join(&changed_path.path);
+    let patcher_path = patcher_folder.join(&changed_path.path);
+    let rebuild_path = patcher_path.clone();
+    let changed_path = changed_path.clone();
+    // Only one reference file is held in memory at a time.
+    let _permit = select! {
+        permit = dl.rebuilds.acquire() => permit.expect("rebuild semaphore is never closed"),
+        _ = dl.cancel.cancelled() => return Ok(false),
+    };
+    let result = tokio::task::spawn_blocking(move || {
+        rebuild_file(&reference, &delta_path, &rebuild_path, &changed_path)
+    })
+    .await
+    .unwrap_or_else(|e| Err(PatchError::join(&patcher_path)(e)));
+    if result.is_err() {
+        dl.progress.reset_file(entry.offset(), None);
+    }
+    result.map(|_| true)
+}
+
+/// Stages a changed file from the shared store, if it holds an intact copy.
+async fn stage_from_store(store: &SharedStore, hash: &str, patcher_path: &Path) -> bool {
+    let store = store.clone();
+    let hash = hash.to_owned();
+    let patcher_path = patcher_path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        match store.stage(&hash, &patcher_path) {
+            Ok(true) => {}
+            Ok(false) => return false,
+            Err(e) => {
+                warn!("failed to stage {} from shared store: {}", hash, e);
+                return false;
+            }
+        }
+        if index::hash_file(&patcher_path).is_ok_and(|staged_hash| staged_hash == hash) {
+            return true;
+        }
+        store.remove(&hash);
+        // Never download into a file that may still be linked to the store.
+        fs::remove_file(&patcher_path).unwrap_or_else(|e| warn!("failed to remove file: {}", e));
+        false
+    })
+    .await
+    .unwrap_or_else(|e| {
+        warn!("failed to stage file from shared store: {}", e);
+        false
+    })
+}
+
+async fn add_to_store(store: &SharedStore, hash: &str, patcher_path: &Path) {
+    let store = store.clone();
+    let hash = hash.to_owned();
+    let patcher_path = patcher_path.to_owned();
+    let result = tokio::task::spawn_blocking(move || store.insert(&hash, &patcher_path)).await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("failed to add file to shared store: {}", e),
+        Err(e) => warn!("failed to add file to shared store: {}", e),
+    }
+}
+
+/// Downloads a single changed file into `patcher_folder` unless a previous run
+/// already staged it completely, and checks it against the manifest hash.
+/// Failed downloads move on to the next mirror, and once every mirror failed
+/// transiently they are retried with exponential backoff, continuing from the
+/// bytes that were already written.
+async fn download_changed_path(
+    dl: &Downloader<'_>,
+    changed_path: &ManifestFile,
+    delta: Option<&ManifestDelta>,
+    patcher_folder: &Path,
+    entry: &ResumeEntry,
+) -> Result<bool, PatchError> {
+    let patcher_path = patcher_folder.join(&changed_path.path);
+    // Whether the size of the transfer is already part of the progress total.
+    let mut size_known = delta.is_some() || changed_path.download_size().is_some();
+    let staged = entry.is_complete() && patcher_path.exists();
+    if let Some(store) = dl.store.filter(|_| !staged) {
+        if stage_from_store(store, &changed_path.hash, &patcher_path).await {
+            let size = match delta {
+                Some(delta) => delta.size,
+                None => changed_path.download_size().unwrap_or(0),
+            };
+            dl.progress.skip_bytes(size);
+            entry.set_offset(size);
+            entry.set_complete(true);
+            return Ok(true);
+        }
+    }
+    if let Some(delta) = delta.filter(|_| !staged) {
+        match apply_delta(dl, changed_path, delta, patcher_folder).await {
+            Ok(applied) => {
+                if applied {
+                    entry.set_offset(delta.size);
+                    entry.set_complete(true);
+                    if let Some(store) = dl.store {
+                        add_to_store(store, &changed_path.hash, &patcher_path).await;
                     }
+                }
+                return Ok(applied);
+            }
+            Err(e) => {
+                warn!(
+                    "falling back to a full download of {}: {}",
+                    changed_path.path, e
+                );
+                dl.progress.reset_file(0, Some(delta.size));
+                size_known = changed_path.download_size().is_some();
+                if let Some(size) = changed_path.download_size() {
+                    dl.progress.add_size(size);
+                }
+            }
+        }
+    }
+    let mut retries = 0;
+    // Status of the last mirror that answered with an error.
+    let mut last_status = None;
+    let mut staged_hash = String::new();
+    for attempt in 1..=HASH_ATTEMPTS {
+        let mut served_by = None;
+        if entry.is_complete() && patcher_path.exists() {
+            if !size_known {
+                dl.progress.add_size(entry.offset());
+            }
+            dl.progress.skip_bytes(entry.offset());
+        } else {
+            entry.set_complete(false);
+            let downloaded = 'download: loop {
+                // The longest delay any mirror asked for, if one of them
+                // failed in a way that may go away on its own.
+                let mut transient = None;
+                for mirror in dl.mirrors.order() {
+                    let url = format!(
+                        "{}/{}{}",
+                        mirror,
+                        changed_path.path,
+                        changed_path.compression.map_or("", Compression::extension)
+                    );
+                    let start = (Instant::now(), entry.offset());
+                    let download = download_file(
+                        dl,
+                        &url,
+                        &patcher_path,
+                        entry,
+                        size_known,
+                        changed_path.compression,
+                    );
+                    let error = match download.await {
+                        Ok(downloaded) => {
+                            if downloaded {
+                                let bytes = entry.offset().saturating_sub(start.1);
+                                dl.mirrors.record_success(mirror, bytes, start.0.elapsed());
+                                served_by = Some(mirror);
+                            }
+                            break 'download downloaded;
+                        }
+                        Err(error) => error,
+                    };
+                    match error {
+                        DownloadError::Transient {
+                            status,
+                            retry_after,
+                        } => {
+                            transient = Some(transient.flatten().max(retry_after));
+                            last_status = status.or(last_status);
+                        }
+                        DownloadError::Rejected { status } => last_status = status.or(last_status),
+                        DownloadError::Fatal(err) => return Err(err),
+                    }
+                    warn!("failed to download {} from {}", changed_path.path, mirror);
+                    dl.mirrors.record_failure(mirror);
+                }
+                let Some(retry_after) = transient.filter(|_| retries < DOWNLOAD_RETRIES) else {
+                    return Err(PatchError::Download {
+                        path: changed_path.path.clone(),
+                        retries,
+                        status: last_status,
+                    });
                 };
+                retries += 1;
+                let delay = retry_after
+                    .unwrap_or_else(|| retry_delay(retries))
+                    .min(RETRY_MAX_DELAY);
+                warn!(
+                    "retrying download of {} in {:?} ({}/{})",
+                    changed_path.path, delay, retries, DOWNLOAD_RETRIES
+                );
+                select! {
+                    _ = dl.cancel.cancelled() => return Ok(false),
+                    _ = tokio::time::sleep(delay) => {}
+                }
             };
-            Some(Ok(patcher_path))
+            if !downloaded {
+                return Ok(false);
+            }
+        }
+        let hash_path = patcher_path.clone();
+        staged_hash = tokio::task::spawn_blocking(move || index::hash_file(&hash_path))
+            .await
+            .map_err(PatchError::join(&patcher_path))?
+            .map_err(PatchError::io(&patcher_path))?;
+        if staged_hash == changed_path.hash {
+            if let Some(store) = dl.store {
+                add_to_store(store, &changed_path.hash, &patcher_path).await;
+            }
+            return Ok(true);
+        }
+        warn!(
+            "hash mismatch for {} (attempt {}/{}): expected {}, got {}",
+            changed_path.path, attempt, HASH_ATTEMPTS, changed_path.hash, staged_hash
+        );
+        if let Some(mirror) = served_by {
+            dl.mirrors.record_failure(mirror);
+        }
+        dl.progress
+            .reset_file(entry.offset(), (!size_known).then_some(entry.offset()));
+        entry.reset();
+    }
+    Err(PatchError::Hash {
+        path: changed_path.path.clone(),
+        expected: changed_path.hash.clone(),
+        actual: staged_hash,
+    })
+}
+
+/// Makes sure the volume of `patcher_folder` can hold every staged file before
+/// anything is downloaded. Backups are renamed into `BACKUP_FOLDER` on the same
+/// volume, which takes no extra space, but the files they replace are kept
+/// until the patch is committed, so nothing is freed while applying either.
+///
+/// Legacy manifests don't list sizes, so their files are left out of the check.
+fn check_disk_space(
+    changed_paths: &[&ManifestFile],
+    patcher_folder: &Path,
+) -> Result<(), PatchError> {
+    let unknown = changed_paths
+        .iter()
+        .filter(|changed_path| changed_path.size.is_none())
+        .count();
+    if unknown > 0 && unknown == changed_paths.len() {
+        warn!("manifest lists no file sizes, skipping the disk space check");
+        return Ok(());
+    }
+    if unknown > 0 {
+        warn!(
+            "manifest lists no size for {} files, leaving them out of the disk space check",
+            unknown
+        );
+    }
+    let required = changed_paths
+        .iter()
+        .filter_map(|changed_path| {
+            let size = changed_path.size?;
+            let staged = fs::metadata(patcher_folder.join(&changed_path.path))
+                .map(|m| m.len())
+                .unwrap_or(0);
+            Some(size.saturating_sub(staged))
         })
-        .try_collect()
-        .or(Err(NETWORK_ERROR))
+        .sum();
+    let available = match fs2::available_space(patcher_folder) {
+        Ok(available) => available,
+        Err(e) => {
+            warn!("failed to query free disk space: {}", e);
+            return Ok(());
+        }
+    };
+    info!(
+        "patch requires {} bytes, {} bytes available",
+        required, available
+    );
+    if required > available {
+        return Err(PatchError::DiskSpace {
+            required,
+            available,
+        });
+    }
+    Ok(())
 }
 
+/// Downloads every changed file into `patcher_folder`, running up to
+/// `concurrency` downloads at once. Returns `false` if the download was
+/// cancelled, in which case the staged files are kept for later.
 async fn download_changed_paths(
-    window: &Window,
+    sink: &dyn ProgressSink,
     client: &reqwest::Client,
-    patcher_url: &str,
-    changed_paths: &[&str],
+    job: &PatchJob,
+    mirrors: &Mirrors,
+    changed_paths: &[&ManifestFile],
     patcher_folder: &Path,
     cancel: CancellationToken,
-) -> Result<(), &'static str> {
-    let total = changed_paths.len();
-    let mut current = 0;
+) -> Result<bool, PatchError> {
+    let progress = Progress::new(sink, State::Downloading, changed_paths.len());
+    let mut resume = load_resume(patcher_folder);
+    // Stops the remaining downloads when one of them fails, without
+    // cancelling the patch itself.
+    let download_cancel = cancel.child_token();
+    let store = job
+        .options
+        .shared_store
+        .clone()
+        .map(|root| SharedStore::new(root, job.options.shared_store_hardlinks));
+    let dl = Downloader {
+        client,
+        game_folder: &job.game_folder,
+        mirrors,
+        limiter: &job.limiter,
+        store: store.as_ref(),
+        progress: &progress,
+        cancel: &download_cancel,
+        rebuilds: Semaphore::new(1),
+    };
+    // Every changed file was just hashed while checking, so picking a delta
+    // only reads the index.
+    let mut index = HashIndex::load(&job.game_folder);
     for changed_path in changed_paths {
-        let req = client
-            .get(format!("{}/{}", patcher_url, changed_path))
-            .send();
-        let mut resp = select! {
-            _ = cancel.cancelled() => return Ok(()),
-            resp = req => resp.or(Err(NETWORK_ERROR))?,
+        let entry = resume.get(&changed_path.path);
+        if !entry.is_some_and(|entry| entry.hash == changed_path.hash) {
+            let entry = ResumeEntry::new(&changed_path.hash);
+            resume.insert(changed_path.path.clone(), entry);
+        }
+    }
+    let jobs: Vec<_> = changed_paths
+        .iter()
+        .map(|&changed_path| {
+            let delta = Some(&changed_path.deltas)
+                .filter(|deltas| !deltas.is_empty())
+                .filter(|_| {
+                    let reference = job.game_folder.join(&changed_path.path);
+                    fs::metadata(reference)
+                        .is_ok_and(|metadata| metadata.len() <= MAX_DELTA_REFERENCE_SIZE)
+                })
+                .and_then(|deltas| {
+                    let hash = index.hash(&job.game_folder, &changed_path.path).ok()?;
+                    deltas.iter().find(|delta| delta.from == hash)
+                });
+            let size = match delta {
+                Some(delta) => Some(delta.size),
+                None => changed_path.download_size(),
+            };
+            if let Some(size) = size {
+                progress.add_size(size);
+            }
+            (changed_path, delta, &resume[&changed_path.path])
+        })
+        .collect();
+    let mut downloads = stream::iter(jobs)
+        .map(|(changed_path, delta, entry)| {
+            download_changed_path(&dl, changed_path, delta, patcher_folder, entry)
+        })
+        .buffer_unordered(job.options.concurrency.max(1));
+
+    // Saved on a timer rather than as files finish, since a single large file
+    // can take much longer than the interval.
+    let mut save_timer = tokio::time::interval_at(
+        tokio::time::Instant::now() + RESUME_SAVE_INTERVAL,
+        RESUME_SAVE_INTERVAL,
+    );
+    let mut result = Ok(true);
+    loop {
+        let download_result = select! {
+            download_result = downloads.next() => match download_result {
+                Some(download_result) => download_result,
+                None => break,
+            },
+            _ = save_timer.tick() => {
+                save_resume(patcher_folder, &resume);
+                continue;
+            }
         };
-        let patcher_path = patcher_folder.join(changed_path);
-        fs::create_dir_all(patcher_path.parent().ok_or(FILE_ERROR)?).or(Err(FILE_ERROR))?;
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(patcher_path)
-            .or(Err(FILE_ERROR))?;
-        while let Some(chunk) = select! {
-            _ = cancel.cancelled() => return Ok(()),
-            chunk = resp.chunk() => chunk.or(Err(NETWORK_ERROR))?
-        } {
-            file.write_all(&chunk).or(Err(NETWORK_ERROR))?;
-        }
-        current += 1;
-        send_event(window, total, current, State::Downloading);
+        match download_result {
+            Ok(true) => progress.complete_file(),
+            Ok(false) => result = result.and(Ok(false)),
+            Err(e) => {
+                download_cancel.cancel();
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
     }
-    Ok(())
+    save_resume(patcher_folder, &resume);
+    result
+}
+
+/// Extracts every changed file from the offline bundle at `bundle` into
+/// `patcher_folder`. Returns `false` if cancelled.
+async fn extract_changed_paths(
+    sink: &dyn ProgressSink,
+    bundle: &Path,
+    changed_paths: &[&ManifestFile],
+    patcher_folder: &Path,
+    cancel: CancellationToken,
+) -> Result<bool, PatchError> {
+    let progress = Progress::new(sink, State::Downloading, changed_paths.len());
+    for size in changed_paths.iter().filter_map(|p| p.size) {
+        progress.add_size(size);
+    }
+    let files: Vec<_> = changed_paths
+        .iter()
+        .map(|p| (p.path.clone(), p.hash.clone()))
+        .collect();
+    let bundle_path = bundle.to_owned();
+    let patcher_folder = patcher_folder.to_owned();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let extract_cancel = cancel.clone();
+    let task = tokio::task::spawn_blocking(move || {
+        bundle::extract(
+            &bundle_path,
+            &files,
+            &patcher_folder,
+            &extract_cancel,
+            |size| {
+                let _ = tx.send(size);
+            },
+        )
+    });
+    while let Some(size) = rx.recv().await {
+        progress.add_bytes(size);
+        progress.complete_file();
+    }
+    task.await.map_err(PatchError::join(bundle))?
 }
 
-fn move_changed_paths(
-    changed_paths: &[&str],
-    source_folder: &Path,
-    target_folder: &Path,
-) -> Result<(), &'static str> {
-    for path in changed_paths {
-        let source_path = source_folder.join(path);
-        let target_path = target_folder.join(path);
-        fs::create_dir_all(target_path.parent().ok_or(FILE_ERROR)?).or(Err(FILE_ERROR))?;
-        fs::rename(&source_path, &target_path).or(Err(FILE_ERROR))?;
+/// Packs the staged files and the manifest into a bundle at `path`.
+async fn export_changed_paths(
+    path: &Path,
+    patcher_resp: &PatcherResponse,
+    changed_paths: &[&ManifestFile],
+    patcher_folder: &Path,
+) -> Result<(), PatchError> {
+    let files: Vec<_> = changed_paths.iter().map(|p| p.path.clone()).collect();
+    let bundle_path = path.to_owned();
+    let patcher_resp = patcher_resp.clone();
+    let patcher_folder = patcher_folder.to_owned();
+    tokio::task::spawn_blocking(move || {
+        bundle::write(&bundle_path, &patcher_resp, &files, &patcher_folder)
+    })
+    .await
+    .map_err(PatchError::join(path))?
+}
+
+/// Writes the journal to a temporary file first, so that a crash while writing
+/// never leaves a truncated journal behind.
+fn write_journal(game_folder: &Path, journal: &Journal) -> Result<(), PatchError> {
+    let path = game_folder.join(JOURNAL_FILE);
+    let partial = game_folder.join(JOURNAL_PARTIAL_FILE);
+    let data = serde_json::to_vec(journal).map_err(|e| PatchError::io(&partial)(e.into()))?;
+    let mut file = fs::File::create(&partial).map_err(PatchError::io(&partial))?;
+    file.write_all(&data).map_err(PatchError::io(&partial))?;
+    file.sync_all().map_err(PatchError::io(&partial))?;
+    fs::rename(&partial, &path).map_err(PatchError::io(&path))
+}
+
+fn remove_journal(game_folder: &Path) -> Result<(), PatchError> {
+    let path = game_folder.join(JOURNAL_FILE);
+    fs::remove_file(&path).map_err(PatchError::io(&path))
+}
+
+/// Returns the folder `path` goes into.
+fn parent_folder(path: &Path) -> Result<&Path, PatchError> {
+    path.parent()
+        .ok_or_else(|| PatchError::io(path)(io::ErrorKind::InvalidInput.into()))
+}
+
+/// Moves `source` to `target`, creating the folder it goes into.
+fn move_file(source: &Path, target: &Path) -> Result<(), PatchError> {
+    let parent = parent_folder(target)?;
+    fs::create_dir_all(parent).map_err(PatchError::io(parent))?;
+    fs::rename(source, target).map_err(PatchError::io(source))
+}
+
+/// Moves the staged files into the game folder, backing up every file that
+/// gets replaced.
+fn move_changed_paths(journal: &Journal, game_folder: &Path) -> Result<(), PatchError> {
+    let backup_folder = journal.patcher_folder.join(BACKUP_FOLDER);
+    for entry in &journal.entries {
+        let source_path = journal.patcher_folder.join(&entry.path);
+        let target_path = game_folder.join(&entry.path);
+        if entry.existed {
+            move_file(&target_path, &backup_folder.join(&entry.path))?;
+        }
+        if entry.delete {
+            continue;
+        }
+        move_file(&source_path, &target_path)?;
     }
     Ok(())
 }
 
-async fn patch_internal(
-    window: &Window,
-    client: reqwest::Client,
-    patcher_url: String,
-    patcher_resp: PatcherResponse,
-    game_folder: &Path,
+/// Undoes a partially applied patch, moving new files back into the staging
+/// folder and restoring the backed up originals.
+fn rollback(journal: &Journal, game_folder: &Path) -> Result<(), PatchError> {
+    let backup_folder = journal.patcher_folder.join(BACKUP_FOLDER);
+    let mut result = Ok(());
+    for entry in journal.entries.iter().rev() {
+        let source_path = journal.patcher_folder.join(&entry.path);
+        let target_path = game_folder.join(&entry.path);
+        let backup_path = backup_folder.join(&entry.path);
+        let restored = if entry.existed && !backup_path.exists() {
+            // The original was never moved away, so nothing to undo.
+            Ok(())
+        } else {
+            let unstaged = if target_path.exists() {
+                fs::rename(&target_path, &source_path).or_else(|_| fs::remove_file(&target_path))
+            } else {
+                Ok(())
+            };
+            unstaged.and_then(|_| {
+                if entry.existed {
+                    fs::rename(&backup_path, &target_path)
+                } else {
+                    Ok(())
+                }
+            })
+        };
+        if let Err(e) = restored {
+            warn!("failed to roll back {}: {}", entry.path, e);
+            if result.is_ok() {
+                result = Err(PatchError::io(&target_path)(e));
+            }
+        }
+    }
+    result
+}
+
+/// Rolls back an apply that was interrupted by a crash, if there is one. The
+/// game folder lock has to be held, otherwise the journal may belong to an
+/// apply that is still running.
+fn recover(game_folder: &Path) -> Result<(), PatchError> {
+    let Ok(data) = fs::read(game_folder.join(JOURNAL_FILE)) else {
+        return Ok(());
+    };
+    warn!("found unfinished patch in {:?}, rolling back", game_folder);
+    // Nothing is moved before the journal is complete, so one that can't be
+    // read means nothing was applied.
+    match serde_json::from_slice::<Journal>(&data) {
+        Ok(journal) => rollback(&journal, game_folder)?,
+        Err(e) => warn!("discarding unreadable patch journal: {}", e),
+    }
+    remove_journal(game_folder)
+}
+
+/// Applies the staged files and deletions so that the game folder ends up either
+/// fully patched or, if anything fails, exactly as it was before.
+fn apply_changed_paths(
+    changed_paths: &[&ManifestFile],
+    deleted_paths: &[&str],
     patcher_folder: &Path,
+    game_folder: &Path,
+) -> Result<(), PatchError> {
+    let backup_folder = patcher_folder.join(BACKUP_FOLDER);
+    if backup_folder.exists() {
+        fs::remove_dir_all(&backup_folder).map_err(PatchError::io(&backup_folder))?;
+    }
+    let journal = Journal {
+        patcher_folder: patcher_folder.to_owned(),
+        entries: changed_paths
+            .iter()
+            .map(|changed_path| JournalEntry {
+                path: changed_path.path.clone(),
+                existed: game_folder.join(&changed_path.path).exists(),
+                delete: false,
+            })
+            .chain(deleted_paths.iter().map(|&path| JournalEntry {
+                path: path.to_owned(),
+                existed: true,
+                delete: true,
+            }))
+            .collect(),
+    };
+    apply_journal(&journal, game_folder)
+}
+
+fn apply_journal(journal: &Journal, game_folder: &Path) -> Result<(), PatchError> {
+    write_journal(game_folder, journal)?;
+    if let Err(e) = move_changed_paths(journal, game_folder) {
+        warn!("failed to apply patch, rolling back: {}", e);
+        // Keep the journal around if the rollback failed, so it gets retried.
+        rollback(journal, game_folder)?;
+        remove_journal(game_folder)?;
+        return Err(e);
+    }
+    remove_journal(game_folder)
+}
+
+/// Returns `false` if the patch was cancelled before being applied.
+async fn patch_internal(
+    sink: &dyn ProgressSink,
+    client: &reqwest::Client,
+    job: &PatchJob,
     cancel: CancellationToken,
-) -> Result<(), &'static str> {
-    send_event(window, 0, 0, State::Checking);
-    let changed_paths = get_changed_paths(&patcher_resp.content, game_folder)?;
-    send_event(window, changed_paths.len(), 0, State::Downloading);
-    download_changed_paths(
-        window,
-        &client,
-        &patcher_url,
-        &changed_paths,
-        patcher_folder,
-        cancel,
-    )
-    .await?;
-    send_event(window, 0, 0, State::Patching);
-    move_changed_paths(&changed_paths, patcher_folder, game_folder)?;
-    set_etag(game_folder, &patcher_resp.etag)?;
-    send_event(window, 0, 0, State::Done);
-    Ok(())
+) -> Result<bool, PatchError> {
+    let game_folder = &job.game_folder;
+    {
+        let _lock = staging::lock_game_folder(game_folder)?;
+        recover(game_folder)?;
+    }
+    let staging = Staging::acquire(game_folder, &job.patcher_resp.etag)?;
+    let patcher_folder = staging.path().to_owned();
+    let manifest = Manifest::parse(&job.patcher_resp.content)?;
+    if let Some(label) = &manifest.label {
+        info!("patcher manifest version: {}", label);
+    }
+    let mut index = if job.repair {
+        HashIndex::default()
+    } else {
+        HashIndex::load(game_folder)
+    };
+    let Changes {
+        changed: changed_paths,
+        deleted: deleted_paths,
+        excluded,
+    } = check_changes(sink, &manifest, game_folder, &mut index)?;
+    if !excluded.is_empty() {
+        info!("excluded outdated files: {:?}", excluded);
+        sink.send_excluded(&excluded);
+    }
+    check_disk_space(&changed_paths, &patcher_folder)?;
+    let completed = match &job.source {
+        PatchSource::Mirrors(mirrors) => {
+            download_changed_paths(
+                sink,
+                client,
+                job,
+                mirrors,
+                &changed_paths,
+                &patcher_folder,
+                cancel,
+            )
+            .await?
+        }
+        PatchSource::Bundle(bundle) => {
+            extract_changed_paths(sink, bundle, &changed_paths, &patcher_folder, cancel).await?
+        }
+    };
+    if !completed {
+        return Ok(false);
+    }
+    if let Some(export) = &job.export {
+        // The staged files are kept, so patching afterwards doesn't download
+        // them again.
+        progress::send_state(sink, State::Patching);
+        export_changed_paths(export, &job.patcher_resp, &changed_paths, &patcher_folder).await?;
+        progress::send_state(sink, State::Done);
+        return Ok(true);
+    }
+    progress::send_state(sink, State::Patching);
+    let _lock = staging::lock_game_folder(game_folder)?;
+    let history_files: Vec<_> = changed_paths
+        .iter()
+        .map(|changed_path| HistoryFile {
+            path: changed_path.path.clone(),
+            change: if game_folder.join(&changed_path.path).exists() {
+                Change::Replaced
+            } else {
+                Change::Added
+            },
+        })
+        .chain(deleted_paths.iter().map(|&path| HistoryFile {
+            path: path.to_owned(),
+            change: Change::Deleted,
+        }))
+        .collect();
+    apply_changed_paths(&changed_paths, &deleted_paths, &patcher_folder, game_folder)?;
+    for changed_path in &changed_paths {
+        index.insert(game_folder, &changed_path.path, &changed_path.hash);
+    }
+    for path in &deleted_paths {
+        index.remove(path);
+    }
+    index.save(game_folder);
+    // Repairs only restore the files of the current version, there is nothing
+    // worth going back to.
+    if !job.repair {
+        History::load(game_folder).record(
+            game_folder,
+            get_etag(game_folder),
+            job.patcher_resp.etag.clone(),
+            history_files,
+            &patcher_folder.join(BACKUP_FOLDER),
+        )?;
+    }
+    set_etag(game_folder, &job.patcher_resp.etag)?;
+    // Staged files are only discarded once they were applied, so a cancelled
+    // or failed patch can be resumed on the next attempt.
+    staging.remove()?;
+    if job.repair {
+        let repaired: Vec<_> = changed_paths.iter().map(|p| p.path.clone()).collect();
+        info!("repaired files: {:?}", repaired);
+        sink.send_repaired(&repaired);
+    }
+    progress::send_state(sink, State::Done);
+    Ok(true)
+}
+
+#[derive(Debug, Serialize)]
+pub struct DryRunFile {
+    path: String,
+    size: Option<u64>,
 }
 
+/// Files a patch would touch, with their download size or, for deleted files,
+/// their current size.
+#[derive(Debug, Serialize)]
+pub struct DryRunReport {
+    added: Vec<DryRunFile>,
+    replaced: Vec<DryRunFile>,
+    deleted: Vec<DryRunFile>,
+    excluded: Vec<String>,
+}
+
+/// Runs only the `Checking` phase of a patch, without downloading or changing
+/// any game file.
+pub fn dry_run(
+    sink: &dyn ProgressSink,
+    patcher_resp: &PatcherResponse,
+    game_folder: &Path,
+) -> Result<DryRunReport, &'static str> {
+    let manifest = Manifest::parse(&patcher_resp.content).map_err(|e| {
+        warn!("invalid patcher manifest: {}", e);
+        e.message()
+    })?;
+    let mut index = HashIndex::load(game_folder);
+    let changes = check_changes(sink, &manifest, game_folder, &mut index).map_err(|e| {
+        warn!("patcher dry run failed: {}", e);
+        e.message()
+    })?;
+    let (replaced, added): (Vec<_>, Vec<_>) = changes
+        .changed
+        .iter()
+        .map(|changed_path| DryRunFile {
+            path: changed_path.path.clone(),
+            size: changed_path.download_size(),
+        })
+        .partition(|file| game_folder.join(&file.path).exists());
+    let deleted = changes
+        .deleted
+        .iter()
+        .map(|path| DryRunFile {
+            path: (*path).to_owned(),
+            size: fs::metadata(game_folder.join(path)).map(|m| m.len()).ok(),
+        })
+        .collect();
+    Ok(DryRunReport {
+        added,
+        replaced,
+        deleted,
+        excluded: changes.excluded,
+    })
+}
+
+/// Runs a patch to completion, reporting its progress and any error to `sink`.
+/// Returns whether the patch was applied, or exported for `PatchJob::export`.
 pub async fn patch(
-    window: Window,
+    sink: impl ProgressSink,
     client: reqwest::Client,
-    patcher_url: String,
-    patcher_resp: PatcherResponse,
-    game_folder: PathBuf,
+    job: PatchJob,
     cancel: CancellationToken,
-) {
-    let tmp_folder = game_folder.join("tmp");
-    if let Err(e) = fs::create_dir_all(&tmp_folder) {
-        warn!("error creating patcher dir: {}", e);
-        send_error(&window, FILE_ERROR);
-        return;
-    }
-    patch_internal(
-        &window,
-        client,
-        patcher_url,
-        patcher_resp,
-        &game_folder,
-        &tmp_folder,
-        cancel,
-    )
-    .await
-    .unwrap_or_else(|e| send_error(&window, e));
-    if let Err(e) = fs::remove_dir_all(&tmp_folder) {
-        warn!("error deleting patcher dir: {}", e);
-        send_error(&window, FILE_ERROR);
+) -> bool {
+    match patch_internal(&sink, &client, &job, cancel).await {
+        Ok(true) => true,
+        Ok(false) => {
+            info!("patcher cancelled, keeping staged files");
+            false
+        }
+        Err(e) => {
+            send_error(&sink, e);
+            false
+        }
     }
 }
 
-fn set_etag(game_folder: &Path, etag: &str) -> Result<(), &'static str> {
+/// Rolls the game folder back to how it was before the recorded patch `id`,
+/// undoing it and every later patch, newest first.
+pub fn revert_patches(game_folder: &Path, id: u64) -> Result<(), PatchError> {
+    let _lock = staging::lock_game_folder(game_folder)?;
+    recover(game_folder)?;
+    let mut history = History::load(game_folder);
+    let Some(pos) = history.entries().iter().position(|entry| entry.id == id) else {
+        return Err(history::ROLLBACK_ERROR.into());
+    };
+    if history.entries()[pos..]
+        .iter()
+        .any(|entry| !entry.has_backup)
+    {
+        warn!("patch backups needed to roll back to {} are gone", id);
+        return Err(history::ROLLBACK_ERROR.into());
+    }
+    let mut index = HashIndex::load(game_folder);
+    let reverted: Vec<_> = history.entries()[pos..].iter().rev().cloned().collect();
+    for entry in reverted {
+        info!(
+            "rolling back patch {} to {}",
+            entry.etag, entry.previous_etag
+        );
+        // The backup folder serves as the staging folder of the original files,
+        // while added files get deleted.
+        let journal = Journal {
+            patcher_folder: History::backup_folder(game_folder, entry.id),
+            entries: entry
+                .files
+                .iter()
+                .map(|file| JournalEntry {
+                    path: file.path.clone(),
+                    existed: game_folder.join(&file.path).exists(),
+                    delete: matches!(file.change, Change::Added),
+                })
+                .filter(|entry| entry.existed || !entry.delete)
+                .collect(),
+        };
+        apply_journal(&journal, game_folder)?;
+        for file in &entry.files {
+            index.remove(&file.path);
+        }
+        index.save(game_folder);
+        set_etag(game_folder, &entry.previous_etag)?;
+        history.pop(game_folder)?;
+    }
+    Ok(())
+}
+
+fn set_etag(game_folder: &Path, etag: &str) -> Result<(), PatchError> {
+    let path = game_folder.join(ETAG_FILE);
     fs::OpenOptions::new()
         .write(true)
         .truncate(true)
         .create(true)
-        .open(game_folder.join(ETAG_FILE))
-        .or(Err(FILE_ERROR))?
-        .write_all(etag.as_bytes())
-        .or(Err(FILE_ERROR))?;
-    Ok(())
+        .open(&path)
+        .and_then(|mut file| file.write_all(etag.as_bytes()))
+        .map_err(PatchError::io(&path))
 }
 
 pub fn get_etag(game_folder: &Path) -> String {
@@ -231,3 +1514,101 @@ pub fn get_etag(game_folder: &Path) -> String {
     };
     etag
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_folder() -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("patcher-test-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn entry(path: &str, existed: bool, delete: bool) -> JournalEntry {
+        JournalEntry {
+            path: path.to_owned(),
+            existed,
+            delete,
+        }
+    }
+
+    fn read(path: &Path) -> Option<String> {
+        fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn failed_apply_restores_game_folder() {
+        let game_folder = temp_folder();
+        let patcher_folder = game_folder.join("patcher.staging-test");
+        fs::create_dir_all(game_folder.join("dat")).unwrap();
+        fs::create_dir_all(patcher_folder.join("dat")).unwrap();
+        fs::write(game_folder.join("dat/a.pac"), "old a").unwrap();
+        fs::write(game_folder.join("dat/c.pac"), "old c").unwrap();
+        fs::write(game_folder.join("old.pac"), "old").unwrap();
+        fs::write(patcher_folder.join("dat/a.pac"), "new a").unwrap();
+        fs::write(patcher_folder.join("dat/b.pac"), "new b").unwrap();
+        // The staged dat/c.pac is missing, so the last move fails.
+        let journal = Journal {
+            patcher_folder: patcher_folder.clone(),
+            entries: vec![
+                entry("dat/a.pac", true, false),
+                entry("dat/b.pac", false, false),
+                entry("old.pac", true, true),
+                entry("dat/c.pac", true, false),
+            ],
+        };
+
+        assert!(apply_journal(&journal, &game_folder).is_err());
+
+        assert_eq!(read(&game_folder.join("dat/a.pac")).unwrap(), "old a");
+        assert_eq!(read(&game_folder.join("dat/c.pac")).unwrap(), "old c");
+        assert_eq!(read(&game_folder.join("old.pac")).unwrap(), "old");
+        assert!(!game_folder.join("dat/b.pac").exists());
+        assert_eq!(read(&patcher_folder.join("dat/a.pac")).unwrap(), "new a");
+        assert_eq!(read(&patcher_folder.join("dat/b.pac")).unwrap(), "new b");
+        assert!(!game_folder.join(JOURNAL_FILE).exists());
+        fs::remove_dir_all(game_folder).unwrap();
+    }
+
+    #[test]
+    fn recover_rolls_back_interrupted_apply() {
+        let game_folder = temp_folder();
+        let patcher_folder = game_folder.join("patcher.staging-test");
+        let backup_folder = patcher_folder.join(BACKUP_FOLDER);
+        fs::create_dir_all(&backup_folder).unwrap();
+        fs::create_dir_all(&patcher_folder).unwrap();
+        // The crash happened after a.pac was replaced and before b.pac was.
+        fs::write(game_folder.join("a.pac"), "new a").unwrap();
+        fs::write(backup_folder.join("a.pac"), "old a").unwrap();
+        fs::write(game_folder.join("b.pac"), "old b").unwrap();
+        fs::write(patcher_folder.join("b.pac"), "new b").unwrap();
+        let journal = Journal {
+            patcher_folder: patcher_folder.clone(),
+            entries: vec![entry("a.pac", true, false), entry("b.pac", true, false)],
+        };
+        write_journal(&game_folder, &journal).unwrap();
+
+        recover(&game_folder).unwrap();
+
+        assert_eq!(read(&game_folder.join("a.pac")).unwrap(), "old a");
+        assert_eq!(read(&game_folder.join("b.pac")).unwrap(), "old b");
+        assert_eq!(read(&patcher_folder.join("a.pac")).unwrap(), "new a");
+        assert!(!game_folder.join(JOURNAL_FILE).exists());
+        fs::remove_dir_all(game_folder).unwrap();
+    }
+
+    #[test]
+    fn recover_discards_truncated_journal() {
+        let game_folder = temp_folder();
+        fs::write(game_folder.join("a.pac"), "old a").unwrap();
+        fs::write(game_folder.join(JOURNAL_FILE), "{\"patcherFolder\": \"x").unwrap();
+
+        recover(&game_folder).unwrap();
+
+        assert_eq!(read(&game_folder.join("a.pac")).unwrap(), "old a");
+        assert!(!game_folder.join(JOURNAL_FILE).exists());
+        fs::remove_dir_all(game_folder).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use serde::Serialize;
+use serde_repr::Serialize_repr;
+use tauri::Window;
+
+use crate::LogPayload;
+
+const EMIT_INTERVAL: Duration = Duration::from_millis(200);
+/// Weight of the latest throughput sample when smoothing the reported speed.
+const SPEED_SMOOTHING: f64 = 0.3;
+
+#[derive(Debug, Serialize_repr, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum State {
+    Checking,
+    Downloading,
+    Patching,
+    Done,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PatcherEvent {
+    pub total: usize,
+    pub current: usize,
+    pub total_bytes: u64,
+    pub current_bytes: u64,
+    /// Bytes per second.
+    pub speed: u64,
+    /// Seconds left, once the speed and some of the sizes are known.
+    pub eta: Option<u64>,
+    pub state: State,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ExcludedPayload<'a> {
+    excluded: &'a [String],
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct RepairPayload<'a> {
+    repaired: &'a [String],
+}
+
+/// Receiver of everything a patcher run reports, which is the launcher window
+/// unless the patcher runs from the command line.
+pub trait ProgressSink: Sync {
+    fn send_event(&self, event: &PatcherEvent);
+
+    /// Reports an error or warning as a Fluent message.
+    fn send_log(&self, payload: LogPayload);
+
+    /// Reports the outdated files that were left alone because they are
+    /// excluded.
+    fn send_excluded(&self, excluded: &[String]);
+
+    /// Reports the files a repair replaced.
+    fn send_repaired(&self, repaired: &[String]);
+}
+
+impl ProgressSink for Window {
+    fn send_event(&self, event: &PatcherEvent) {
+        self.emit("patcher", event)
+            .unwrap_or_else(|e| warn!("failed to emit message: {}", e));
+    }
+
+    fn send_log(&self, payload: LogPayload) {
+        self.emit("log", payload)
+            .unwrap_or_else(|e| warn!("failed to emit message: {}", e));
+    }
+
+    fn send_excluded(&self, excluded: &[String]) {
+        self.emit("patcher_excluded", ExcludedPayload { excluded })
+            .unwrap_or_else(|e| warn!("failed to emit message: {}", e));
+    }
+
+    fn send_repaired(&self, repaired: &[String]) {
+        self.emit("patcher_repair", RepairPayload { repaired })
+            .unwrap_or_else(|e| warn!("failed to emit message: {}", e));
+    }
+}
+
+pub fn send_state(sink: &dyn ProgressSink, state: State) {
+    sink.send_event(&PatcherEvent {
+        total: 0,
+        current: 0,
+        total_bytes: 0,
+        current_bytes: 0,
+        speed: 0,
+        eta: None,
+        state,
+    });
+}
+
+struct ProgressInner {
+    current: usize,
+    current_bytes: u64,
+    total_bytes: u64,
+    /// Files whose size is already part of `total_bytes`.
+    sized: usize,
+    speed: f64,
+    sample_bytes: u64,
+    sample_start: Instant,
+    last_emit: Instant,
+}
+
+/// File and byte progress of a patcher phase, shared between concurrent
+/// downloads and reported to the sink at most every `EMIT_INTERVAL`.
+pub struct Progress<'a> {
+    sink: &'a dyn ProgressSink,
+    state: State,
+    total: usize,
+    inner: Mutex<ProgressInner>,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(sink: &'a dyn ProgressSink, state: State, total: usize) -> Self {
+        let now = Instant::now();
+        let progress = Self {
+            sink,
+            state,
+            total,
+            inner: Mutex::new(ProgressInner {
+                current: 0,
+                current_bytes: 0,
+                total_bytes: 0,
+                sized: 0,
+                speed: 0.0,
+                sample_bytes: 0,
+                sample_start: now,
+                last_emit: now,
+            }),
+        };
+        progress.update(true, |_| {});
+        progress
+    }
+
+    /// Adds the size of a file to the total once it is known.
+    pub fn add_size(&self, size: u64) {
+        self.update(false, |inner| {
+            inner.total_bytes += size;
+            inner.sized += 1;
+        });
+    }
+
+    /// Removes the bytes of a file that has to be transferred again, along
+    /// with its size if that was only learned while transferring it.
+    pub fn reset_file(&self, bytes: u64, size: Option<u64>) {
+        self.update(false, |inner| {
+            inner.current_bytes = inner.current_bytes.saturating_sub(bytes);
+            if let Some(size) = size {
+                inner.total_bytes = inner.total_bytes.saturating_sub(size);
+                inner.sized = inner.sized.saturating_sub(1);
+            }
+        });
+    }
+
+    /// Records bytes that were already available, without counting them
+    /// towards the speed.
+    pub fn skip_bytes(&self, bytes: u64) {
+        self.update(false, |inner| inner.current_bytes += bytes);
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.update(false, |inner| {
+            inner.current_bytes += bytes;
+            inner.sample_bytes += bytes;
+        });
+    }
+
+    pub fn complete_file(&self) {
+        self.update(true, |inner| inner.current += 1);
+    }
+
+    fn update<F: FnOnce(&mut ProgressInner)>(&self, force: bool, f: F) {
+        let mut inner = self.inner.lock().unwrap();
+        f(&mut inner);
+        let now = Instant::now();
+        let elapsed = now - inner.sample_start;
+        if elapsed >= EMIT_INTERVAL {
+            let sample = inner.sample_bytes as f64 / elapsed.as_secs_f64();
+            inner.speed = if inner.speed == 0.0 {
+                sample
+            } else {
+                inner.speed + (sample - inner.speed) * SPEED_SMOOTHING
+            };
+            inner.sample_bytes = 0;
+            inner.sample_start = now;
+        }
+        if !force && now - inner.last_emit < EMIT_INTERVAL {
+            return;
+        }
+        inner.last_emit = now;
+
+        // Until every size is known, assume the remaining files are as large
+        // as the average of the known ones.
+        let estimated_bytes = match inner.sized {
+            0 => None,
+            sized if sized < self.total => {
+                Some(inner.total_bytes as f64 * self.total as f64 / sized as f64)
+            }
+            _ => Some(inner.total_bytes as f64),
+        };
+        let eta = estimated_bytes
+            .filter(|_| inner.speed > 0.0)
+            .map(|bytes| ((bytes - inner.current_bytes as f64).max(0.0) / inner.speed) as u64);
+        self.sink.send_event(&PatcherEvent {
+            total: self.total,
+            current: inner.current,
+            total_bytes: inner.total_bytes,
+            current_bytes: inner.current_bytes,
+            speed: inner.speed as u64,
+            eta,
+            state: self.state,
+        });
+    }
+}
@@ -9,9 +9,10 @@ use serde_repr::{Deserialize_repr, Serialize_repr};
 use tokio::select;
 use tokio_util::sync::CancellationToken;
 
-use crate::{endpoint::Endpoint, patcher};
+use crate::{endpoint::Endpoint, manifest, patcher};
 
 const NETWORK_ERROR: &str = "launcher-network-error";
+const SIGNATURE_HEADER: &str = "X-Patch-Signature";
 
 pub enum Error {
     Cancellation,
@@ -126,14 +127,34 @@ pub struct AuthResponse {
     pub characters: Vec<CharacterData>,
     pub mez_fez: Option<MezFesData>,
     pub patch_server: String,
+    #[serde(default)]
+    pub patch_mirrors: Vec<String>,
+}
+
+impl AuthResponse {
+    pub fn patch_servers(&self, endpoint: &Endpoint) -> Vec<String> {
+        let mut urls: Vec<String> = Vec::new();
+        let candidates = std::iter::once(&self.patch_server)
+            .chain(&self.patch_mirrors)
+            .chain(&endpoint.patch_mirrors);
+        for url in candidates {
+            let url = url.trim_end_matches('/');
+            if !url.is_empty() && !urls.iter().any(|u| u == url) {
+                urls.push(url.to_owned());
+            }
+        }
+        urls
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EmptyResponse {}
 
+#[derive(Clone)]
 pub struct PatcherResponse {
     pub etag: String,
     pub content: String,
+    pub signature: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -209,13 +230,30 @@ impl<T: DeserializeOwned> JsonRequest<T> {
 }
 
 pub struct PatcherRequest {
-    request: RequestBuilder,
+    requests: Vec<RequestBuilder>,
     cancel: CancellationToken,
+    patch_key: Option<String>,
 }
 
 impl PatcherRequest {
     pub async fn send(self) -> Result<Option<PatcherResponse>, Error> {
-        let resp = send(self.request, self.cancel).await?;
+        let mut result = Err(Error::Backend(patcher::NETWORK_ERROR.into()));
+        for request in self.requests {
+            result = Self::send_one(request, &self.cancel, self.patch_key.as_deref()).await;
+            match &result {
+                Ok(_) | Err(Error::Cancellation) => break,
+                Err(e) => warn!("patcher request failed: {}", e),
+            }
+        }
+        result
+    }
+
+    async fn send_one(
+        request: RequestBuilder,
+        cancel: &CancellationToken,
+        patch_key: Option<&str>,
+    ) -> Result<Option<PatcherResponse>, Error> {
+        let resp = send(request, cancel.clone()).await?;
         let status = resp.status().as_u16();
         if status == 304 {
             return Ok(None);
@@ -226,11 +264,28 @@ impl PatcherRequest {
             .and_then(|v| v.to_str().ok())
             .ok_or(Error::Server(status, patcher::NETWORK_ERROR.into()))?
             .to_owned();
-        let content = resp.text().await.map_err(|e| {
+        let signature = resp
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_owned());
+        let content = resp.bytes().await.map_err(|e| {
             warn!("failed to read body of patcher request {}", e);
             Error::Server(status, patcher::NETWORK_ERROR.into())
         })?;
-        Ok(Some(PatcherResponse { etag, content }))
+        if let Some(patch_key) = patch_key {
+            manifest::verify_signature(patch_key, &content, signature.as_deref())
+                .map_err(|e| Error::Backend(e.into()))?;
+        }
+        let content = String::from_utf8(content.to_vec()).map_err(|e| {
+            warn!("patcher manifest is not valid utf-8 {}", e);
+            Error::Server(status, patcher::NETWORK_ERROR.into())
+        })?;
+        Ok(Some(PatcherResponse {
+            etag,
+            content,
+            signature,
+        }))
     }
 }
 
@@ -328,11 +383,21 @@ pub fn export_save_request(
 pub fn patcher_request(
     client: &reqwest::Client,
     cancel: CancellationToken,
-    url: &str,
+    urls: &[String],
     client_etag: &str,
+    patch_key: Option<&str>,
 ) -> PatcherRequest {
-    let request = client
-        .get(format!("{}/check", url))
-        .header("If-None-Match", client_etag);
-    PatcherRequest { request, cancel }
+    let requests = urls
+        .iter()
+        .map(|url| {
+            client
+                .get(format!("{}/check", url))
+                .header("If-None-Match", client_etag)
+        })
+        .collect();
+    PatcherRequest {
+        requests,
+        cancel,
+        patch_key: patch_key.map(|k| k.to_owned()),
+    }
 }
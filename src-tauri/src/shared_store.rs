@@ -0,0 +1,127 @@
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+
+/// Extensions of files the launcher and the game edit in place, such as
+/// `mhf.ini`, which are always copied so that changing them in one game folder
+/// doesn't change them in every other.
+const MUTABLE_EXTENSIONS: &[&str] = &["ini"];
+
+/// Content-addressed store of patch files shared by every game folder, where
+/// each file is kept once under its SHA-256 so that folders patched after the
+/// first one don't download it again.
+#[derive(Debug, Clone)]
+pub struct SharedStore {
+    root: PathBuf,
+    /// Hardlinks files instead of copying them, which saves space but means
+    /// that a game file edited in place changes the stored blob and the same
+    /// file in every other game folder linked to it. Blobs changed this way
+    /// fail their hash check and are dropped the next time they are used.
+    hardlink: bool,
+}
+
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+impl SharedStore {
+    pub fn new(root: PathBuf, hardlink: bool) -> Self {
+        Self { root, hardlink }
+    }
+
+    fn blob_path(&self, hash: &str) -> Option<PathBuf> {
+        // Hashes come from the manifest, so they must not be able to name
+        // anything outside the store.
+        if !is_sha256(hash) {
+            return None;
+        }
+        let hash = hash.to_ascii_lowercase();
+        Some(self.root.join(&hash[..2]).join(&hash))
+    }
+
+    /// Links or copies `source` to `target`, where `game_file` is whichever of
+    /// the two belongs to a game folder.
+    fn link_or_copy(&self, source: &Path, target: &Path, game_file: &Path) -> io::Result<()> {
+        let mutable = game_file.extension().is_some_and(|extension| {
+            MUTABLE_EXTENSIONS
+                .iter()
+                .any(|mutable| extension.eq_ignore_ascii_case(mutable))
+        });
+        if self.hardlink && !mutable && fs::hard_link(source, target).is_ok() {
+            return Ok(());
+        }
+        fs::copy(source, target).map(|_| ())
+    }
+
+    /// Places the stored file with `hash` at `target`, returning `false` if the
+    /// store does not have it.
+    pub fn stage(&self, hash: &str, target: &Path) -> io::Result<bool> {
+        let Some(blob) = self.blob_path(hash).filter(|blob| blob.exists()) else {
+            return Ok(false);
+        };
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if target.exists() {
+            fs::remove_file(target)?;
+        }
+        self.link_or_copy(&blob, target, target)?;
+        Ok(true)
+    }
+
+    /// Adds the verified file at `source` to the store, unless it already has
+    /// it.
+    pub fn insert(&self, hash: &str, source: &Path) -> io::Result<()> {
+        let Some(blob) = self.blob_path(hash).filter(|blob| !blob.exists()) else {
+            return Ok(());
+        };
+        let parent = blob.parent().ok_or(io::ErrorKind::InvalidInput)?;
+        fs::create_dir_all(parent)?;
+        // Other launcher instances may read the store, so blobs only appear
+        // once they are complete.
+        let partial = parent.join(format!("{}.{:016x}", hash, rand::random::<u64>()));
+        self.link_or_copy(source, &partial, source)?;
+        fs::rename(&partial, &blob).inspect_err(|_| {
+            let _ = fs::remove_file(&partial);
+        })
+    }
+
+    /// Drops a blob whose content no longer matches its hash.
+    pub fn remove(&self, hash: &str) {
+        if let Some(blob) = self.blob_path(hash) {
+            warn!("removing corrupted shared store blob {}", hash);
+            fs::remove_file(blob).unwrap_or_else(|e| warn!("failed to remove blob: {}", e));
+        }
+    }
+
+    /// Deletes every blob whose hash is not in `referenced`, along with partial
+    /// blobs left by crashed runs, and returns the number of bytes freed.
+    pub fn collect_garbage(&self, referenced: &HashSet<String>) -> io::Result<u64> {
+        let mut freed = 0;
+        let Ok(folders) = fs::read_dir(&self.root) else {
+            return Ok(0);
+        };
+        for folder in folders {
+            let folder = folder?;
+            if !folder.file_type()?.is_dir() {
+                continue;
+            }
+            for blob in fs::read_dir(folder.path())? {
+                let blob = blob?;
+                let name = blob.file_name().to_string_lossy().into_owned();
+                if is_sha256(&name) && referenced.contains(&name) {
+                    continue;
+                }
+                let size = blob.metadata()?.len();
+                fs::remove_file(blob.path())?;
+                freed += size;
+            }
+        }
+        info!("shared store garbage collection freed {} bytes", freed);
+        Ok(freed)
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use fs2::FileExt;
+use log::{info, warn};
+
+use crate::patcher::PatchError;
+
+const STAGING_PREFIX: &str = "patcher.staging-";
+const LOCK_FILE: &str = "patcher.lock";
+/// ETag of the patch whose files are staged.
+const ETAG_FILE: &str = "patcher.staged";
+
+/// Folder a patch is staged in before being applied, uniquely named inside the
+/// game folder so that it is on the same volume without ever clashing with
+/// user files or another launcher instance. It stays locked for as long as
+/// this is alive, so folders left behind by crashed or cancelled runs are the
+/// unlocked ones.
+///
+/// The lock is a `.lock` file next to the folder rather than inside it, since
+/// Windows can't delete or rename a folder holding an open file.
+#[derive(Debug)]
+pub struct Staging {
+    path: PathBuf,
+    lock: fs::File,
+}
+
+fn lock_path(folder: &Path) -> PathBuf {
+    let mut path = folder.as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+fn open_lock(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+fn try_lock(path: &Path) -> Option<fs::File> {
+    let file = open_lock(path)
+        .map_err(|e| warn!("failed to open staging lock {:?}: {}", path, e))
+        .ok()?;
+    file.try_lock_exclusive().ok()?;
+    Some(file)
+}
+
+/// Deletes a staging folder and then its lock, which is only released once
+/// nothing is left for another instance to take over.
+fn discard(path: &Path, lock: fs::File) -> io::Result<()> {
+    if path.exists() {
+        fs::remove_dir_all(path)?;
+    }
+    fs::remove_file(lock_path(path))?;
+    drop(lock);
+    Ok(())
+}
+
+/// Locks the game folder itself, waiting for any other launcher instance that
+/// is changing it. Patches are applied, rolled back and recovered with the
+/// lock held, so a journal found while holding it was left by a crash.
+pub fn lock_game_folder(game_folder: &Path) -> Result<fs::File, PatchError> {
+    let path = game_folder.join(LOCK_FILE);
+    let file = open_lock(&path).map_err(PatchError::io(&path))?;
+    file.lock_exclusive().map_err(PatchError::io(&path))?;
+    Ok(file)
+}
+
+impl Staging {
+    /// Locks a staging folder for the patch `etag`, taking over the one left
+    /// behind by an interrupted run of the same patch so that it can be
+    /// resumed, and removing any other abandoned one.
+    pub fn acquire(game_folder: &Path, etag: &str) -> Result<Self, PatchError> {
+        let entries = fs::read_dir(game_folder).map_err(PatchError::io(game_folder))?;
+        let mut resumed = None;
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(STAGING_PREFIX) {
+                continue;
+            }
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            let path = match name.strip_suffix(".lock") {
+                _ if is_dir => entry.path(),
+                // Locks whose folder is gone were left by a crash while
+                // discarding it.
+                Some(folder) if !game_folder.join(folder).exists() => game_folder.join(folder),
+                _ => continue,
+            };
+            let Some(lock) = try_lock(&lock_path(&path)) else {
+                info!("staging folder {:?} is in use", path);
+                continue;
+            };
+            let staged_etag = fs::read_to_string(path.join(ETAG_FILE)).unwrap_or_default();
+            if is_dir && resumed.is_none() && staged_etag == etag {
+                info!("resuming patch staged in {:?}", path);
+                resumed = Some(Self { path, lock });
+            } else {
+                info!("removing abandoned staging folder {:?}", path);
+                discard(&path, lock)
+                    .unwrap_or_else(|e| warn!("error deleting staging folder: {}", e));
+            }
+        }
+        if let Some(staging) = resumed {
+            return Ok(staging);
+        }
+        // The lock exists before the folder does, so other instances never
+        // see the folder unlocked.
+        let path = game_folder.join(format!("{}{:016x}", STAGING_PREFIX, rand::random::<u64>()));
+        let lock_path = lock_path(&path);
+        let lock = open_lock(&lock_path)
+            .and_then(|lock| lock.lock_exclusive().map(|_| lock))
+            .map_err(PatchError::io(&lock_path))?;
+        fs::create_dir(&path).map_err(PatchError::io(&path))?;
+        let etag_path = path.join(ETAG_FILE);
+        fs::write(&etag_path, etag).map_err(PatchError::io(&etag_path))?;
+        Ok(Self { path, lock })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Deletes the folder once its files were applied.
+    pub fn remove(self) -> Result<(), PatchError> {
+        discard(&self.path, self.lock).map_err(PatchError::io(&self.path))
+    }
+}
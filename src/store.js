@@ -31,6 +31,12 @@ const storePrivate = reactive({
   links: [],
   characters: [],
   messages: [],
+  patchServer: "",
+  hasPatch: false,
+  patcherExclusions: [],
+  patcherChanges: null,
+  patcherHistory: [],
+  patcherDonePage: CHARACTERS_PAGE,
   remoteMessages: [],
 
   authLoading: false,
@@ -49,6 +55,10 @@ const storePrivate = reactive({
   patcher: {
     total: 0,
     current: 0,
+    totalBytes: 0,
+    currentBytes: 0,
+    speed: 0,
+    eta: null,
     state: DONE_PATCHER,
   },
 
@@ -77,6 +87,10 @@ export const storeMut = reactive({
   editEndpoint: null,
   serverlistUrl: "",
   messagelistUrl: "",
+  patcherConcurrency: 4,
+  patcherRateLimit: 0,
+  patcherSharedStore: null,
+  patcherSharedStoreHardlinks: false,
 });
 
 export function logText(level, text) {
@@ -145,15 +159,55 @@ export function updatePatcher(patcher) {
   }
 }
 export const patcherPercentage = computed(() => {
-  switch (storePrivate.patcher.state) {
+  const patcher = storePrivate.patcher;
+  switch (patcher.state) {
     case CHECKING_PATCHER:
-      return 0;
     case DOWNLOADING_PATCHER:
-      return storePrivate.patcher.current / (storePrivate.patcher.total || 1);
+      if (patcher.totalBytes && patcher.current < patcher.total) {
+        return Math.min(patcher.currentBytes / patcher.totalBytes, 1);
+      }
+      return patcher.current / (patcher.total || 1);
     default:
       return 1;
   }
 });
+function formatBytes(bytes) {
+  const units = ["B", "KB", "MB", "GB"];
+  let unit = 0;
+  while (bytes >= 1024 && unit < units.length - 1) {
+    bytes /= 1024;
+    unit++;
+  }
+  return `${bytes.toFixed(unit ? 1 : 0)} ${units[unit]}`;
+}
+export const patcherChangeGroups = computed(() => {
+  const changes = storePrivate.patcherChanges;
+  if (!changes) return [];
+  const format = ({ path, size }) =>
+    size == null ? path : `${path} (${formatBytes(size)})`;
+  return [
+    ["patcher-changes-added", changes.added.map(format)],
+    ["patcher-changes-replaced", changes.replaced.map(format)],
+    ["patcher-changes-deleted", changes.deleted.map(format)],
+    ["patcher-changes-excluded", changes.excluded],
+  ]
+    .filter(([, files]) => files.length)
+    .map(([label, files]) => ({ label, files }));
+});
+function formatDuration(seconds) {
+  const minutes = Math.floor(seconds / 60);
+  return `${minutes}:${String(seconds % 60).padStart(2, "0")}`;
+}
+export const patcherSpeed = computed(() => {
+  const { state, speed, eta } = storePrivate.patcher;
+  if (state !== DOWNLOADING_PATCHER || !speed) {
+    return null;
+  }
+  return getMessage("patcher-speed", {
+    speed: formatBytes(speed),
+    eta: eta === null ? "?" : formatDuration(eta),
+  });
+});
 export const patcherLog = computed(() => {
   switch (storePrivate.patcher.state) {
     case CHECKING_PATCHER:
@@ -215,6 +269,21 @@ watch(
   async (messagelistUrl) =>
     await handleInvoke("set_messagelist_url", { messagelistUrl })
 );
+watch(
+  () => storeMut.patcherConcurrency,
+  async (concurrency) =>
+    await handleInvoke("set_patcher_concurrency", { concurrency })
+);
+watch(
+  () => storeMut.patcherRateLimit,
+  async (rateLimit) =>
+    await handleInvoke("set_patcher_rate_limit", { rateLimit })
+);
+watch(
+  () => [storeMut.patcherSharedStore, storeMut.patcherSharedStoreHardlinks],
+  async ([sharedStore, hardlinks]) =>
+    await handleInvoke("set_patcher_shared_store", { sharedStore, hardlinks })
+);
 
 export const effectiveBanners = computed(() =>
   store.banners.length
@@ -256,6 +325,11 @@ export async function initStore() {
   storeMut.gameFolder = data.gameFolder;
   storeMut.serverlistUrl = data.serverlistUrl;
   storeMut.messagelistUrl = data.messagelistUrl;
+  storeMut.patcherConcurrency = data.patcherOptions.concurrency;
+  storeMut.patcherRateLimit = data.patcherOptions.rateLimit;
+  storeMut.patcherSharedStore = data.patcherOptions.sharedStore;
+  storeMut.patcherSharedStoreHardlinks =
+    data.patcherOptions.sharedStoreHardlinks;
   storePrivate.endpoints = data.endpoints;
   storePrivate.remoteEndpoints = data.remoteEndpoints;
   storePrivate.currentEndpoint = data.currentEndpoint;
@@ -311,6 +385,7 @@ export function dialogAddEndpoint() {
     gamePort: null,
     gamePath: null,
     version: GAME_VERSIONS[0],
+    patchKey: null,
   };
   storePrivate.editEndpointNew = true;
   storePrivate.dialogKind = SERVERS_DIALOG;
@@ -367,12 +442,79 @@ export async function dialogStartPatcher() {
   storePrivate.authLoading = true;
   await hanldeDialogClose(async () => {
     await handleInvoke("patcher_start");
+    storePrivate.hasPatch = false;
+    storePrivate.patcherDonePage = CHARACTERS_PAGE;
+    storeMut.page = PATCHER_PAGE;
+  });
+}
+async function startPatcherCommand(cmd, args, donePage) {
+  storePrivate.authLoading = true;
+  try {
+    await handleInvoke(cmd, args);
+    storePrivate.patcherDonePage = donePage;
     storeMut.page = PATCHER_PAGE;
+  } catch (error) {
+    storePrivate.authLoading = false;
+    throw error;
+  }
+}
+export async function startRepairPatcher() {
+  await startPatcherCommand("patcher_repair", {}, CHARACTERS_PAGE);
+}
+export async function startImportPatcher(path) {
+  await startPatcherCommand("patcher_import", { path }, LOGIN_PAGE);
+}
+export async function startExportPatcher(path) {
+  await startPatcherCommand("patcher_export", { path }, LOGIN_PAGE);
+}
+export function reportRepairPatcher({ repaired }) {
+  logMessage("info", "patcher-repair-done", { count: repaired.length });
+}
+export function reportExcludedPatcher({ excluded }) {
+  logMessage("warning", "patcher-excluded-outdated", {
+    count: excluded.length,
+    files: excluded.join(", "),
+  });
+}
+export async function loadPatcherExclusions() {
+  storePrivate.patcherExclusions = await handleInvoke(
+    "get_patcher_exclusions"
+  );
+}
+export async function setPatcherExclusions(patterns) {
+  await handleInvoke("set_patcher_exclusions", { patterns });
+  storePrivate.patcherExclusions = patterns;
+}
+export async function loadPatcherChanges() {
+  try {
+    storePrivate.patcherChanges = await handleInvoke("patcher_dry_run");
+  } finally {
+    // The dry run reports its checking progress like a real patch.
+    storePrivate.patcher.state = DONE_PATCHER;
+  }
+}
+export async function loadPatcherHistory() {
+  storePrivate.patcherHistory = await handleInvoke("get_patcher_history");
+}
+export async function rollbackPatcher(entry) {
+  storePrivate.authLoading = true;
+  try {
+    await handleInvoke("patcher_rollback", { id: entry.id });
+    logMessage("info", "patcher-rollback-done", { etag: entry.etag });
+  } finally {
+    storePrivate.authLoading = false;
+    await loadPatcherHistory();
+  }
+}
+export async function cleanPatcherSharedStore() {
+  const freed = await handleInvoke("patcher_store_gc");
+  logMessage("info", "patcher-shared-store-cleaned", {
+    size: formatBytes(freed),
   });
 }
 export async function completePatcher() {
   storePrivate.authLoading = false;
-  storeMut.page = CHARACTERS_PAGE;
+  storeMut.page = storePrivate.patcherDonePage;
 }
 export async function cancelPatcher() {
   await handleInvoke("patcher_stop");
@@ -390,6 +532,7 @@ export async function setEndpoints(endpoints, remote) {
     ...endpoint,
     launcherPort: endpoint.launcherPort || null,
     gamePort: endpoint.gamePort || null,
+    patchKey: endpoint.patchKey || null,
   }));
   let currentEndpoint;
   if (remote) {
@@ -440,7 +583,10 @@ async function doAuth(kind, message) {
       message
     );
     storePrivate.characters = response.characters;
+    storePrivate.patchServer = response.patchServer;
+    storePrivate.hasPatch = hasPatch;
     if (hasPatch) {
+      storePrivate.patcherChanges = null;
       storePrivate.dialogKind = PATCHER_DIALOG;
       storePrivate.dialogOpen = true;
     } else {